@@ -1,291 +1,7239 @@
-use nix::sys::mman::{mprotect, ProtFlags};
-use rand;
-use std::alloc::{alloc, dealloc, Layout};
-use std::collections::{HashMap, HashSet, LinkedList};
-use std::ffi::c_void;
-use std::ptr;
-
-/*  AArch64 のレジスタ
-    x0 ~ x30: 汎用 64bit register
-    v0 ~ v31: SIMD register (下位 64bit は d0 ~ d30 register)
-    sp: スタックポインタ
-
-    呼び出し規則 (AAPCS64)
-    x30: リンクレジスタ                                   => 関数リターン時の戻りアドレスを保存する (ret 命令で使われる)
-    x29: フレームレジスタ                                 => スタック領域の好きな場所を指せる
-    x19 ~ x28: callee (呼び出された関数) 側保存レジスタ    => 関数からリターンする前に復帰しなければならない
-    x18: プラットフォームレジスタ                          => OS 依存 (ユーザーが使うことは非推奨)
-    x17: 第二プロシージャ呼び出し間一時レジスタ             => リンカによって使われる
-    x16: 第一プロシージャ呼び出し間一時レジスタ             => リンカによって使われる
-    x9 ~ x15: 一時レジスタ
-    x8: 返り値用レジスタ (アドレス渡し用)                  => 大きなデータを渡したい時用
-    x0 ~ x7: 引数・返り値用レジスタ
-
-    v16 ~ v31: 一時レジスタ
-    d8 ~ d15: callee 保存レジスタ                         => 関数からリターンする前に復帰しなければならない
-    d0 ~ d7: 引数・返り値用レジスタ
-*/
-
-// Registers
-#[repr(C)]  // 内部メモリ表現が C 言語のそれと同じになるように設定 -> アセンブリで定義した関数に渡す
-struct Registers {      
-    // callee 保存レジスタ: callee 側が責任をもって保存しなければならない -> heap 上に退避
-    d8: u64, d9: u64, d10: u64, d11: u64, d12: u64, d13: u64, d14: u64, d15: u64,
-    x19: u64, x20: u64, x21: u64, x22: u64, x23: u64, x24: u64, x25: u64, x26: u64, x27: u64, x28: u64,
-    x30: u64,   // リンクレジスタ: コンテキストスイッチから戻ってくるために必要
-    sp: u64,    // スタックポインタ: スタック復元のために必要
-}   // それ以外のレジスタはスタック上に退避する
-
-impl Registers {
-    fn new(sp: u64) -> Self {
-        Registers { 
-            d8: 0, d9: 0, d10: 0, d11: 0, d12: 0, d13: 0, d14: 0, d15: 0, 
-            x19: 0, x20: 0, x21: 0, x22: 0, x23: 0, x24: 0, x25: 0, x26: 0, x27: 0, x28: 0, 
-            x30: entry_point as u64,    // コンテキストスイッチされた際に entry_point 関数が最初に呼び出されるようにする 
-            sp, 
-        }
-    }
-}
-
-extern "C" {
-    fn set_context(ctx: *mut Registers) -> u64;         // 返り値が 0 => set_context からの返り値; 返り値が 1 => switch_context が呼ばれた
-    fn switch_context(ctx: *const Registers) -> !;      // !: never 型: return しないことを示す
-}
-
-// Context
-type Entry = fn();      // スレッド開始時に実行する関数の型
-const PAGE_SIZE: usize = 4 * 1024;      // 4KiB: Linux の仮想メモリ
-struct Context {
-    regs: Registers,
-    stack: *mut u8,
-    stack_layout: Layout,   // dealloc() するために必要
-    entry: Entry,
-    thread_id: u64,
-} 
-
-impl Context {
-    fn get_regs_mut(&mut self) -> *mut Registers {      // Registers へのポインタ
-        &mut self.regs as *mut Registers
-    }
-
-    fn get_regs(&self) -> *const Registers {
-        &self.regs as *const Registers
-    }
-
-    fn new(func: Entry, stack_size: usize, thread_id: u64) -> Self {
-        let layout = Layout::from_size_align(stack_size, PAGE_SIZE).unwrap();   // PAGE_SIZE にアライメントされたメモリレイアウトを指定
-        let stack = unsafe {alloc(layout)};     // スタック用メモリ領域を確保
-        unsafe {mprotect(stack as *mut c_void, PAGE_SIZE, ProtFlags::PROT_NONE).unwrap()};  // スタックオーバーフロー検出用のガードページを設定
-
-        let regs = Registers::new(stack as u64 + stack_size as u64);    // Registers 構造体の初期化
-
-        Context { 
-            regs: regs, 
-            stack: stack, 
-            stack_layout: layout,  
-            entry: func, 
-            thread_id: thread_id, 
-        }
-    }
-}
-
-// map: key_of_actor -> LinkedList<Message>: actor ごとの message queue
-struct MappedList<T> {
-    map: HashMap<u64, LinkedList<T>>,
-}
-
-impl<T> MappedList<T> {
-    fn new() -> Self {
-        MappedList { map: HashMap::new() }
-    }
-
-    fn push_back(&mut self, key: u64, val: T) {
-        if let Some(list) = self.map.get_mut(&key) {    // 対応する actor が存在すれば val を追加 (push back)
-            list.push_back(val);
-        } else {        // actor が存在しなければ、新たに追加
-            let mut list = LinkedList::new();
-            list.push_back(val);
-            self.map.insert(key, list);
-        }
-    }
-
-    fn pop_front(&mut self, key: u64) -> Option<T> {        // key に対応するリストから取り出す (pop front)
-        if let Some(list) = self.map.get_mut(&key) {
-            let val = list.pop_front();
-            if list.len() == 0 {
-                self.map.remove(&key);
-            }
-
-            val
-        } else {
-            None 
-        }
-    }
-
-    fn clear(&mut self) {
-        self.map.clear();
-    }
-}
-
-// マルチスレッド化する場合には mutex などで保護する必要がある; 簡単のため global 変数を用いる
-static mut CTX_MAIN: Option<Box<Registers>> = None;     // main() のコンテキスト
-static mut UNUSED_STACK: (*mut u8, Layout) = (ptr::null_mut(), Layout::new::<u8>());    // free() すべきスタック領域へのポインタとレイアウト
-static mut CONTEXTS: LinkedList<Box<Context>> = LinkedList::new();      // threads queue
-static mut ID: *mut HashSet<u64> = ptr::null_mut();     // thread id の集合
-static mut MESSAGES: *mut MappedList<u64> = ptr::null_mut();
-static mut WAITING: *mut HashMap<u64, Box<Context>> = ptr::null_mut();
-
-fn get_id() -> u64 {
-    loop {
-        let rnd = rand::random::<u64>();
-        unsafe {
-            if !(*ID).contains(&rnd) {
-                (*ID).insert(rnd);
-                return rnd;
-            }
-        }
-    }
-}
-
-pub fn spawn(func: Entry, stack_size: usize) -> u64 {
-    unsafe {
-        let id = get_id();
-        CONTEXTS.push_back(Box::new(Context::new(func, stack_size, id)));   // queue の最後尾に新規作成
-        schedule();     // コンテキストスイッチ
-        id
-    }
-}
-
-// main() から一度だけ呼ばれ、グローバル変数の初期化と解放を行う
-pub fn spawn_from_main(func: Entry, stack_size: usize) {
-    unsafe {
-        if let Some(_) = &CTX_MAIN {
-            panic!("spawn_from_main is called twice");
-        }
-
-        // main() 関数用のコンテキストを生成
-        CTX_MAIN = Some(Box::new(Registers::new(0)));
-        if let Some(ctx) = &mut CTX_MAIN {
-            // global 変数の初期化
-            let mut msgs = MappedList::new();
-            MESSAGES = &mut msgs as *mut MappedList<u64>;
-            let mut waiting = HashMap::new();
-            WAITING = &mut waiting as *mut HashMap<u64, Box<Context>>;
-            let mut ids = HashSet::new();
-            ID = &mut ids as *mut HashSet<u64>;
-        
-            // CONTEXTS の初期化 + func の thread を起動
-            if set_context(&mut **ctx as *mut Registers) == 0 {     // main() のコンテキスト保存
-                CONTEXTS.push_back(Box::new(Context::new(func, stack_size, get_id())));
-                let first = CONTEXTS.front().unwrap();
-                switch_context(first.get_regs());       // func 実行
-            }   // func() からリターンして main() に戻ってきた
-
-            // 後処理
-            rm_unused_stack();      // 不要なスタック解放
-            CTX_MAIN = None;
-            CONTEXTS.clear();
-            MESSAGES = ptr::null_mut();
-            WAITING = ptr::null_mut();
-            ID = ptr::null_mut();
-
-            // msgs, waiting, ids を明示的にリセット -> ライフタイムを保証
-            msgs.clear();
-            waiting.clear();
-            ids.clear();
-        }
-    }
-}
-
-pub fn schedule() {
-    unsafe {
-        if CONTEXTS.len() == 1 {
-            return;
-        }
-
-        // queue からコンテキストを pop_front -> push_back
-        let mut ctx = CONTEXTS.pop_front().unwrap();
-        let regs = ctx.get_regs_mut();      // get register data
-        CONTEXTS.push_back(ctx);
-
-        if set_context(regs) == 0 {     // 今の実行プロセスの状態を保存; 
-            let next = CONTEXTS.front().unwrap();
-            switch_context((**next).get_regs());    // コンテキストスイッチ
-        }
-
-        rm_unused_stack();      // 不要なスタック領域を削除
-    }
-}
-
-unsafe fn rm_unused_stack() {
-    if UNUSED_STACK.0 != ptr::null_mut() {
-        mprotect(UNUSED_STACK.0 as *mut c_void, PAGE_SIZE, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE).unwrap();
-        dealloc(UNUSED_STACK.0, UNUSED_STACK.1);
-        UNUSED_STACK = (ptr::null_mut(), Layout::new::<u8>());
-    }
-}
-
-// actor 間の message のやり取り
-pub fn send(key: u64, msg: u64) {
-    unsafe {    
-        // message 送信
-        (*MESSAGES).push_back(key, msg);
-        if let Some(ctx) = (*WAITING).remove(&key) {
-            CONTEXTS.push_back(ctx);
-        }
-    }
-    schedule();     // 協調的マルチタスク: actor 側が scheduling 実行
-}
-
-pub fn receive() -> Option<u64> {
-    unsafe {
-        let key = CONTEXTS.front().unwrap().thread_id;      // thread_id
-        
-        if let Some(msg) = (*MESSAGES).pop_front(key) {     // message がすでに queue に存在する
-            return Some(msg);
-        }   // 以下、message が queue に存在しない
-
-        if CONTEXTS.len() == 1 {    // 実行可能スレッドがほかに存在しない -> deadlock    
-            panic!("deadlock");     // 実際の設計ではタイムアウトを設けて処理
-        }
-
-        // このスレッドを受信待ち状態にし、コンテキストスイッチ
-        let mut ctx = CONTEXTS.pop_front().unwrap();
-        let regs = ctx.get_regs_mut();
-        (*WAITING).insert(key, ctx);
-        if set_context(regs) == 0 {
-            let next = CONTEXTS.front().unwrap();
-            switch_context((**next).get_regs());
-        }   // return しない
-
-        // 以下は疑似覚醒対策
-        rm_unused_stack();
-        (*MESSAGES).pop_front(key)
-    }
-}
-
-// entry_point 関数
-extern "C" fn entry_point() {
-    unsafe {
-        let ctx = CONTEXTS.front().unwrap();
-        ((**ctx).entry)();      // thread の entry 関数実行 
-        // entry() の終了 <=> thread の終了
-        
-        // thread 終了時の処理
-        let ctx = CONTEXTS.pop_front().unwrap();
-        (*ID).remove(&ctx.id);
-        UNUSED_STACK = ((*ctx).stack, (*ctx).stack_layout);     // コンテキストスイッチ後にスタック領域を解放するよう予約
-
-        match CONTEXTS.front() {        // 次のスレッドにコンテキストスイッチ
-            Some(c) => {
-                switch_context((**c).get_regs());
-            },
-            None => {       // main() へコンテキストスイッチ
-                if let Some(c) = &CTX_MAIN {
-                    switch_context(&**c as *const Registers);
-                }
-            }
-        };
-    }
-    panic!("entry point");      // 到達しないはず
-}
\ No newline at end of file
+use crate::platform::{LinuxPlatform, Platform, PAGE_SIZE};
+use nix::errno::Errno;
+use rand;
+use std::alloc::Layout;
+use std::collections::{HashMap, HashSet, LinkedList, VecDeque};
+use std::any::Any;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::collections::BTreeMap;
+use std::ops::{ControlFlow, Deref, DerefMut};
+use std::fmt::{self, Write};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::task::{Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+/*  AArch64 のレジスタ
+    x0 ~ x30: 汎用 64bit register
+    v0 ~ v31: SIMD register (下位 64bit は d0 ~ d30 register)
+    sp: スタックポインタ
+
+    呼び出し規則 (AAPCS64)
+    x30: リンクレジスタ                                   => 関数リターン時の戻りアドレスを保存する (ret 命令で使われる)
+    x29: フレームレジスタ                                 => スタック領域の好きな場所を指せる
+    x19 ~ x28: callee (呼び出された関数) 側保存レジスタ    => 関数からリターンする前に復帰しなければならない
+    x18: プラットフォームレジスタ                          => OS 依存 (ユーザーが使うことは非推奨)
+    x17: 第二プロシージャ呼び出し間一時レジスタ             => リンカによって使われる
+    x16: 第一プロシージャ呼び出し間一時レジスタ             => リンカによって使われる
+    x9 ~ x15: 一時レジスタ
+    x8: 返り値用レジスタ (アドレス渡し用)                  => 大きなデータを渡したい時用
+    x0 ~ x7: 引数・返り値用レジスタ
+
+    v16 ~ v31: 一時レジスタ
+    d8 ~ d15: callee 保存レジスタ                         => 関数からリターンする前に復帰しなければならない
+    d0 ~ d7: 引数・返り値用レジスタ
+*/
+
+// Registers
+#[repr(C)]  // 内部メモリ表現が C 言語のそれと同じになるように設定 -> アセンブリで定義した関数に渡す
+struct Registers {      
+    // callee 保存レジスタ: callee 側が責任をもって保存しなければならない -> heap 上に退避
+    d8: u64, d9: u64, d10: u64, d11: u64, d12: u64, d13: u64, d14: u64, d15: u64,
+    x19: u64, x20: u64, x21: u64, x22: u64, x23: u64, x24: u64, x25: u64, x26: u64, x27: u64, x28: u64,
+    x30: u64,   // リンクレジスタ: コンテキストスイッチから戻ってくるために必要
+    sp: u64,    // スタックポインタ: スタック復元のために必要
+}   // それ以外のレジスタはスタック上に退避する
+
+impl Registers {
+    // entry: x30 に置くトランポリンのアドレス; backend ごとに異なる呼び出し規則のトランポリンを差し替えられるようにする
+    fn new(sp: u64, entry: u64) -> Self {
+        debug_assert!(sp.is_multiple_of(16), "AAPCS64 requires sp to be 16-byte aligned: sp = {:#x}", sp);
+        Registers { 
+            d8: 0, d9: 0, d10: 0, d11: 0, d12: 0, d13: 0, d14: 0, d15: 0, 
+            x19: 0, x20: 0, x21: 0, x22: 0, x23: 0, x24: 0, x25: 0, x26: 0, x27: 0, x28: 0, 
+            x30: entry,     // コンテキストスイッチされた際に entry (トランポリン) が最初に呼び出されるようにする 
+            sp, 
+        }
+    }
+}
+
+extern "C" {
+    fn set_context(ctx: *mut Registers) -> u64;         // 返り値が 0 => set_context からの返り値; 返り値が 1 => switch_context が呼ばれた
+    #[link_name = "switch_context"]
+    fn raw_switch_context(ctx: *const Registers);       // 戻らないはずだが、Rust 側では戻り得るものとして宣言する (下記)
+}
+
+/*  ctx のコンテキストへ切り替える: 切り替え先の set_context から 1 で戻る (または entry_point が始まる) ため、呼び出し元には戻らない
+    extern 宣言を -> ! にすると、asm の誤りで戻ってしまった場合に compiler の仮定が崩れ、壊れた状態のまま後続の処理が実行される
+    -> () で宣言して呼び、戻ってきたら ABI の不具合として即座に abort する
+*/
+unsafe fn switch_context(ctx: *const Registers) -> ! {
+    if let Some(next) = CONTEXTS.front_mut() {
+        if next.get_regs() == ctx {     // 切り替え先は常に CONTEXTS の先頭 (main() へ戻る場合を除く)
+            next.run_count += 1;
+        }
+    }
+    raw_switch_context(ctx);
+    eprintln!("fatal: switch_context returned to its caller (context {:p}); the context-switch assembly is broken", ctx);
+    process::abort();
+}
+
+// Context
+type Entry = fn();      // スレッド開始時に実行する関数の型
+type Task = Box<dyn FnOnce() -> u64>;      // 実際に Context が保持する処理: 返り値は actor の結果
+
+fn from_entry(func: Entry) -> Task {
+    Box::new(move || {
+        func();
+        0
+    })
+}
+
+// spawn される Entry: debug build では関数ごとの spawn 回数を数える
+fn spawn_entry(func: Entry) -> Task {
+    #[cfg(debug_assertions)]
+    unsafe {
+        count_spawn(func);
+    }
+    from_entry(func)
+}
+
+const MIN_USABLE_STACK: usize = 2 * PAGE_SIZE;     // これより小さい使用可能領域には警告を出す
+// 初期 sp とスタック最上位の間に空ける余白: leaf 関数などが sp の直上を使っても確保領域の外に出ないように (16 の倍数)
+const RED_ZONE: usize = 128;
+pub const MAX_PRIORITY: u8 = 7;         // 優先度は 0 ~ MAX_PRIORITY
+const DEFAULT_PRIORITY: u8 = 3;
+struct Context {
+    regs: Registers,
+    stack: *mut u8,
+    stack_layout: Layout,   // dealloc() するために必要
+    guard_size: usize,      // スタック先頭のガードページの大きさ
+    entry: Option<Task>,    // 実行開始時に取り出す
+    thread_id: u64,
+    trap_exit: bool,        // true: リンク先の異常終了を message として受け取る
+    priority: u8,           // 大きいほど優先して実行される
+    local: Option<u64>,     // actor-local storage: actor 終了時に Context ごと破棄される
+    recv_streak: usize,     // mailbox が空にならずに続けて receive した数 (receive_budget 用)
+    parent: Option<u64>,    // spawn した actor (main() から起動された root は None)
+    owns_stack: bool,       // false: 呼び出し側が用意したスタック (spawn_with_stack) -> runtime は解放しない
+    created: u64,           // 生成時の論理時計 (TICKS)
+    run_count: u64,         // コンテキストスイッチで実行権を得た回数
+    preempt_off: u32,       // preempt_disable の入れ子の深さ: 0 でなければ要求された reschedule を遅延する
+    weight: u32,            // 一度実行権を得たら続けて使える slice (schedule の呼び出し) の数
+    slices_left: u32,       // 今回の実行で残っている slice: 0 になるまで schedule は他へ切り替えない
+} 
+
+impl Context {
+    fn get_regs_mut(&mut self) -> *mut Registers {      // Registers へのポインタ
+        &mut self.regs as *mut Registers
+    }
+
+    fn get_regs(&self) -> *const Registers {
+        &self.regs as *const Registers
+    }
+
+    fn new(func: Task, stack_size: usize, thread_id: u64) -> Result<Self, ActorError> {
+        let platform = unsafe {PLATFORM};
+        let (align, guard_size) = unsafe {(CONFIG.stack_align, CONFIG.guard_pages * platform.page_size())};
+        // ガードページを除いた使用可能領域がなければ、起動直後に SIGSEGV するだけなので拒否
+        let usable = stack_size.saturating_sub(guard_size + RED_ZONE);
+        if usable == 0 {
+            return Err(ActorError::StackTooSmall { stack_size, guard_size });
+        }
+        if usable < MIN_USABLE_STACK {
+            eprintln!("warning: stack_size {} leaves only {} usable bytes after the guard page(s)", stack_size, usable);
+        }
+        // 生存中の actor のスタック合計が予算を超えるなら確保しない
+        let (live, budget) = unsafe {(STACK_TOTAL, CONFIG.total_stack_budget)};
+        if live.saturating_add(stack_size) > budget {
+            return Err(ActorError::StackBudgetExceeded { stack_size, live, budget });
+        }
+
+        let layout = Layout::from_size_align(stack_size, align).unwrap();   // stack_align にアライメントされたメモリレイアウトを指定
+        let stack = unsafe {platform.alloc_stack(layout)};     // スタック用メモリ領域を確保
+        if stack.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        // スタックオーバーフロー検出用のガードページを設定; 失敗 (heap への mprotect が禁止された環境など) したらスタックを返して error
+        if let Err(errno) = unsafe {platform.protect(stack, guard_size)} {
+            eprintln!("spawn: failed to set up the stack guard page: {}", errno);
+            unsafe {platform.dealloc_stack(stack, layout)};
+            return Err(ActorError::GuardSetupFailed(errno));
+        }
+
+        let ctx = Context::on_stack(func, stack, layout, guard_size, thread_id);
+        unsafe {STACK_TOTAL += stack_size};     // assert で panic したスタックは数えない (teardown で未解放と判定しない)
+        Ok(ctx)
+    }
+
+    // 確保済みのスタック上に Context を作る
+    fn on_stack(func: Task, stack: *mut u8, layout: Layout, guard_size: usize, thread_id: u64) -> Self {
+        let top = stack as u64 + layout.size() as u64;      // スタックの最上位アドレス: stack と stack_size がともに 16 の倍数でなければならない
+        debug_assert!(top.is_multiple_of(16), "misaligned top of stack: stack = {:p}, stack_size = {}", stack, layout.size());
+        let sp = top - RED_ZONE as u64;     // 最上位に余白を残す -> sp より上への書き込みが隣の確保領域に届かない
+        let regs = Registers::new(sp, entry_point as extern "C" fn() as usize as u64);    // Registers 構造体の初期化
+
+        Context { 
+            regs: regs, 
+            stack: stack, 
+            stack_layout: layout,  
+            guard_size,
+            entry: Some(func), 
+            thread_id: thread_id, 
+            trap_exit: false,
+            priority: DEFAULT_PRIORITY,
+            local: None,
+            recv_streak: 0,
+            parent: None,
+            owns_stack: true,
+            created: unsafe {TICKS},
+            run_count: 0,
+            preempt_off: 0,
+            weight: 1,
+            slices_left: 0,
+        }
+    }
+}
+
+// runtime 操作の失敗
+#[derive(Clone, Debug, PartialEq)]
+pub enum ActorError {
+    GuardSetupFailed(Errno),    // ガードページの mprotect に失敗
+    StackTooSmall { stack_size: usize, guard_size: usize },     // ガードページを除くと使用可能なスタックが残らない
+    StackBudgetExceeded { stack_size: usize, live: usize, budget: usize },     // 確保すると total_stack_budget を超える
+    WaitCycleBroken,            // 待ち合わせの循環 (deadlock) を断ち切るために待機を打ち切られた
+    NotInActorContext,          // actor の外 (main() など) から actor 用の操作が呼ばれた
+    BarrierBroken(u64),         // barrier の参加者が到着せずに終了した
+    MailboxFull { id: u64, bytes: usize, limit: usize },     // 積むと宛先の mailbox が max_mailbox_bytes を超える (bytes は積まれている分)
+    MlockFailed(Errno),         // スタックの mlock に失敗 (RLIMIT_MEMLOCK を超えたなど)
+}
+
+impl fmt::Display for ActorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActorError::GuardSetupFailed(errno) => write!(f, "failed to set up the stack guard page: {}", errno),
+            ActorError::StackTooSmall { stack_size, guard_size } => {
+                write!(f, "stack_size {} leaves no usable stack after {} bytes of guard page(s)", stack_size, guard_size)
+            },
+            ActorError::StackBudgetExceeded { stack_size, live, budget } => {
+                write!(f, "stack_size {} would exceed the total stack budget ({} of {} bytes in use)", stack_size, live, budget)
+            },
+            ActorError::WaitCycleBroken => write!(f, "wait was aborted to break a deadlocked wait cycle"),
+            ActorError::MailboxFull { id, bytes, limit } => {
+                write!(f, "mailbox of actor {} is full ({} of {} bytes)", id, bytes, limit)
+            },
+            ActorError::BarrierBroken(id) => write!(f, "barrier {} is broken: a participant exited", id),
+            ActorError::NotInActorContext => write!(f, "no actor is running (called outside of spawn_from_main's actors)"),
+            ActorError::MlockFailed(errno) => write!(f, "failed to lock the stack into memory (check RLIMIT_MEMLOCK): {}", errno),
+        }
+    }
+}
+
+impl std::error::Error for ActorError {}
+
+// actor の終了理由
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExitReason {
+    Normal(u64),    // entry がリターンした: 値は actor の結果
+    Panicked,       // entry が panic した
+    Killed,         // kill() された
+    StackOverflow,  // ガードページに触れた (SIGSEGV から回復できた場合のみ; できなければ従来どおりプロセスが落ちる)
+    Error(String),  // spawn_try の関数が Err を返した: Debug 表現を保持する
+}
+
+// Runtime の設定: spawn_from_main の前後どちらでも set_config() で差し替え可能
+#[derive(Clone, Debug)]
+pub struct RuntimeConfig {
+    /*  send() の最後に schedule() するか
+        true:  送信ごとに yield -> 受信側がすぐに動くため低レイテンシ
+        false: enqueue と起床だけ行い yield しない -> まとめて送ってから yield_now() することでスループット向上
+    */
+    pub send_yields: bool,
+    pub stack_align: usize,     // スタックのアライメント: 2 のべき乗かつ Platform::page_size() の倍数
+    pub guard_pages: usize,     // スタック先頭に置くガードページの数
+    pub total_stack_budget: usize,      // 生存中の actor のスタック (ガードページ込み) の合計の上限 (バイト)
+    /*  deadlock 時に待ち合わせの循環 (join / call_with_correlation の待ち相手をたどる) を探し、
+        見つかれば循環中の一つの actor の待機を ActorError::WaitCycleBroken で打ち切る
+        false (既定) または循環が見つからなければ従来どおり panic
+    */
+    pub break_wait_cycles: bool,
+    pub on_deadlock: DeadlockAction,    // receive が deadlock を検出した時の動作
+    // mailbox が空にならないまま receive がこの数だけ続けて message を返したら、次の receive の前に一度 yield する (usize::MAX で無制限)
+    pub receive_budget: usize,
+    // spawn 系の関数の最後に schedule() するか; false なら生成した側がそのまま実行を続ける
+    pub spawn_yields: bool,
+    // actor ごとの mailbox に積める message の大きさ (既定は 8 バイト, send_sized で申告) の合計の上限 (usize::MAX で無制限); 超える send は捨てられ、dropped_messages で数える
+    pub max_mailbox_bytes: usize,
+    /*  同じ Entry 関数がこの回数 spawn されたら警告する (debug build のみ; usize::MAX で無効)
+        loop の中で誤って actor を作り続けている場合の検出用で、error にはしない
+    */
+    pub spawn_warn_threshold: usize,
+    /*  receive が message を受け取れないまま受信待ちをやり直せる回数 (debug build のみ)
+        起床したのに message がない状態が続くのは、他の箇所の起床処理の不具合 -> 超えたら診断を出して panic (無限ループの代わり)
+    */
+    pub max_reparks: usize,
+    pub on_empty: OnEmpty,      // 実行可能な actor がなくなった時に main() へ戻る前の動作
+    pub dedup_window: usize,    // send_dedup が宛先ごとに覚えておく直近の dedup_id の数
+}
+
+/*  最後の実行可能な actor が終了した時の動作
+    RunActor(id): 受信待ち・停止中の id を一度だけ起こし、後処理 (記録や集計) をさせてから main() に戻る
+        起こす時点で shutdown() と同じ状態にする -> id の receive は受信待ちせず None を返す
+        id が存在しない (終了済み) 場合や id 自身が最後に終了した場合は ReturnToMain と同じ
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnEmpty {
+    ReturnToMain,   // すぐに main() へ戻る (従来の動作)
+    RunActor(u64),
+}
+
+// receive で実行可能な actor がほかになく、OS スレッドでの処理もない (deadlock) 時の動作
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeadlockAction {
+    Panic,          // panic する (従来の動作)
+    ReturnNone,     // receive が None を返す
+    Park(u32),      // send_external による外部からの起床を最大 n 回 (1 回 DEADLOCK_ROUND) 待ち、届かなければ panic
+}
+
+impl RuntimeConfig {
+    pub const fn new() -> Self {
+        RuntimeConfig { 
+            send_yields: true, 
+            stack_align: PAGE_SIZE, 
+            guard_pages: 1,
+            total_stack_budget: usize::MAX,     // 上限なし
+            break_wait_cycles: false,
+            on_deadlock: DeadlockAction::Panic,
+            receive_budget: usize::MAX,
+            spawn_yields: true,
+            max_mailbox_bytes: usize::MAX,
+            spawn_warn_threshold: 1000,
+            max_reparks: 1_000_000,
+            on_empty: OnEmpty::ReturnToMain,
+            dedup_window: 64,
+        }
+    }
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig::new()
+    }
+}
+
+/*  mailbox に積まれる 1 件: payload と、send_tracked で送られた場合の ack id, max_mailbox_bytes で数える大きさ
+    ack を payload の値ではなく積まれた message 自体に結び付ける (同じ値の message や、pool_receive で奪われた message を取り違えない)
+    bytes は通常 MESSAGE_BYTES; send_sized で payload が指す領域 (heap の buffer など) の大きさを申告できる
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+    pub msg: u64,
+    pub ack_id: Option<u64>,
+    pub bytes: usize,
+}
+
+impl Envelope {
+    fn new(msg: u64) -> Self {
+        Envelope { msg, ack_id: None, bytes: MESSAGE_BYTES }
+    }
+}
+
+// Mailbox::push の結果: 容量の都合で捨てた message があれば、どちらを捨てたかと一緒に返す
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PushResult {
+    Pushed,
+    Evicted(Envelope),      // 積んだ代わりに溢れた古い message を捨てた
+    Rejected(Envelope),     // 満杯のため届いた message を積まなかった
+}
+
+/*  actor ごとの mailbox の格納方式: spawn 時に MailboxKind で選ぶ (既定は FIFO)
+    send / receive はこの trait を経由して message を出し入れする
+    push は容量の都合で捨てた message を PushResult で返す
+    -> Evicted なら捨てた古い message の ack を失敗させ、Rejected なら send 自体を失敗 (捨てた数に数える) とする
+*/
+pub trait Mailbox {
+    fn push(&mut self, env: Envelope) -> PushResult;
+    // send_prio 用: 優先度を扱わない mailbox では push と同じ
+    fn push_prio(&mut self, env: Envelope, _prio: u8) -> PushResult {
+        self.push(env)
+    }
+    fn pop(&mut self) -> Option<Envelope>;
+    fn peek(&self) -> Option<u64>;     // 次に pop される message の payload (取り出さない)
+    fn len(&self) -> usize;
+}
+
+// 到着順
+pub struct FifoMailbox {
+    list: LinkedList<Envelope>,
+}
+
+impl Mailbox for FifoMailbox {
+    fn push(&mut self, env: Envelope) -> PushResult {
+        self.list.push_back(env);
+        PushResult::Pushed
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        self.list.pop_front()
+    }
+
+    fn peek(&self) -> Option<u64> {
+        self.list.front().map(|env| env.msg)
+    }
+
+    fn len(&self) -> usize {
+        self.list.len()
+    }
+}
+
+// 優先度の高い順; 同じ優先度の中では到着順 (push は優先度 0 = 最低)
+pub struct PriorityMailbox {
+    list: LinkedList<(u8, Envelope)>,
+}
+
+impl Mailbox for PriorityMailbox {
+    fn push(&mut self, env: Envelope) -> PushResult {
+        self.list.push_back((0, env));
+        PushResult::Pushed
+    }
+
+    fn push_prio(&mut self, env: Envelope, prio: u8) -> PushResult {
+        let pos = self.list.iter().position(|(p, _)| *p < prio).unwrap_or(self.list.len());
+        let mut rest = self.list.split_off(pos);
+        self.list.push_back((prio, env));
+        self.list.append(&mut rest);
+        PushResult::Pushed
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        self.list.pop_front().map(|(_, env)| env)
+    }
+
+    fn peek(&self) -> Option<u64> {
+        self.list.front().map(|(_, env)| env.msg)
+    }
+
+    fn len(&self) -> usize {
+        self.list.len()
+    }
+}
+
+// 容量 capacity の到着順; 溢れたら最も古い message を捨てる
+pub struct RingMailbox {
+    buf: VecDeque<Envelope>,
+    capacity: usize,
+}
+
+impl Mailbox for RingMailbox {
+    fn push(&mut self, env: Envelope) -> PushResult {
+        let evicted = if self.buf.len() == self.capacity {
+            self.buf.pop_front()
+        } else {
+            None
+        };
+        self.buf.push_back(env);
+        evicted.map_or(PushResult::Pushed, PushResult::Evicted)
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        self.buf.pop_front()
+    }
+
+    fn peek(&self) -> Option<u64> {
+        self.buf.front().map(|env| env.msg)
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+// 容量 capacity の到着順; 溢れたら新しく届いた message を捨てる
+pub struct BoundedMailbox {
+    buf: VecDeque<Envelope>,
+    capacity: usize,
+}
+
+impl Mailbox for BoundedMailbox {
+    fn push(&mut self, env: Envelope) -> PushResult {
+        if self.buf.len() < self.capacity {
+            self.buf.push_back(env);
+            PushResult::Pushed
+        } else {
+            PushResult::Rejected(env)
+        }
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        self.buf.pop_front()
+    }
+
+    fn peek(&self) -> Option<u64> {
+        self.buf.front().map(|env| env.msg)
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum MailboxKind {
+    Fifo,
+    Priority,
+    Ring(usize),                    // 容量
+    Bounded(usize),                 // 容量
+    Custom(fn() -> Box<dyn Mailbox>),   // 独自の実装
+}
+
+impl MailboxKind {
+    fn create(self) -> Box<dyn Mailbox> {
+        match self {
+            MailboxKind::Fifo => Box::new(FifoMailbox { list: LinkedList::new() }),
+            MailboxKind::Priority => Box::new(PriorityMailbox { list: LinkedList::new() }),
+            MailboxKind::Ring(capacity) => {
+                assert!(capacity > 0, "RingMailbox capacity must be at least 1");
+                Box::new(RingMailbox { buf: VecDeque::with_capacity(capacity), capacity })
+            },
+            MailboxKind::Bounded(capacity) => {
+                assert!(capacity > 0, "BoundedMailbox capacity must be at least 1");
+                Box::new(BoundedMailbox { buf: VecDeque::with_capacity(capacity), capacity })
+            },
+            MailboxKind::Custom(f) => f(),
+        }
+    }
+}
+
+// map: key_of_actor -> Box<dyn Mailbox>: actor ごとの message queue
+// 空になった mailbox は削除し、次の message で kinds に従って作り直す
+// message の出し入れはすべて MappedList を通し、積まれている Envelope::bytes の合計を bytes に保つ
+struct MappedList {
+    map: IdMap<Box<dyn Mailbox>>,
+    kinds: IdMap<MailboxKind>,     // FIFO 以外を選んだ actor の mailbox の種類
+    bytes: IdMap<usize>,           // 空でない mailbox に積まれている message の大きさの合計
+}
+
+impl MappedList {
+    fn new() -> Self {
+        MappedList { map: IdMap::default(), kinds: IdMap::default(), bytes: IdMap::default() }
+    }
+
+    fn sub_bytes(&mut self, key: u64, n: usize) {
+        if let Some(total) = self.bytes.get_mut(&key) {
+            *total -= n;
+            if *total == 0 {
+                self.bytes.remove(&key);
+            }
+        }
+    }
+
+    fn set_kind(&mut self, key: u64, kind: MailboxKind) {
+        self.kinds.insert(key, kind);
+    }
+
+    // 種類を変え、積まれている message を取り出し順に新しい mailbox へ積み直す (容量のある種類では溢れた分を捨てて返す)
+    fn reconfigure(&mut self, key: u64, kind: MailboxKind) -> Vec<Envelope> {
+        self.set_kind(key, kind);
+        let mut dropped = Vec::new();
+        if let Some(mut old) = self.map.remove(&key) {
+            self.bytes.remove(&key);        // 積み直した分だけ数え直す
+            while let Some(env) = old.pop() {
+                if let PushResult::Evicted(env) | PushResult::Rejected(env) = self.push(key, env, 0) {
+                    dropped.push(env);
+                }
+            }
+        }
+        dropped
+    }
+
+    fn mailbox(&mut self, key: u64) -> &mut Box<dyn Mailbox> {     // 対応する mailbox がなければ新たに追加
+        let kind = self.kinds.get(&key).copied().unwrap_or(MailboxKind::Fifo);
+        self.map.entry(key).or_insert_with(|| kind.create())
+    }
+
+    // 追跡されない message を積む (容量で捨てられても通知先がない)
+    fn push_back(&mut self, key: u64, val: u64) {
+        self.push(key, Envelope::new(val), 0);
+    }
+
+    fn push(&mut self, key: u64, env: Envelope, prio: u8) -> PushResult {
+        let result = self.mailbox(key).push_prio(env, prio);
+        match result {
+            PushResult::Pushed => *self.bytes.entry(key).or_insert(0) += env.bytes,
+            PushResult::Evicted(old) => {
+                *self.bytes.entry(key).or_insert(0) += env.bytes;
+                self.sub_bytes(key, old.bytes);
+            },
+            PushResult::Rejected(_) => {},
+        }
+        result
+    }
+
+    fn pop_front(&mut self, key: u64) -> Option<Envelope> {        // key に対応する mailbox から取り出す
+        if let Some(mailbox) = self.map.get_mut(&key) {
+            let val = mailbox.pop();
+            if mailbox.len() == 0 {
+                self.map.remove(&key);
+            }
+            if let Some(env) = val {
+                self.sub_bytes(key, env.bytes);
+            }
+
+            val
+        } else {
+            None 
+        }
+    }
+
+    // 空になった mailbox は削除されているため、以下の参照系は key がなければ空として扱う (error にしない)
+    fn len(&self, key: u64) -> usize {      // key に対応する mailbox の要素数; mailbox がなければ 0
+        self.map.get(&key).map_or(0, |mailbox| mailbox.len())
+    }
+
+    fn peek(&self, key: u64) -> Option<u64> {   // mailbox がなければ None
+        self.map.get(&key).and_then(|mailbox| mailbox.peek())
+    }
+
+    fn bytes(&self, key: u64) -> usize {      // 積まれている message の大きさの合計; mailbox がなければ 0
+        self.bytes.get(&key).copied().unwrap_or(0)
+    }
+
+    fn remove(&mut self, key: u64) {        // key に対応する mailbox ごと削除
+        self.map.remove(&key);
+        self.kinds.remove(&key);
+        self.bytes.remove(&key);
+    }
+
+    fn drain_all(&mut self) -> HashMap<u64, Vec<Envelope>> {     // 全ての mailbox を空にして中身を返す (mailbox の種類は残す)
+        self.bytes.clear();
+        self.map.drain().map(|(key, mut mailbox)| {
+            let msgs = std::iter::from_fn(|| mailbox.pop()).collect();
+            (key, msgs)
+        }).collect()
+    }
+
+    fn is_fifo(&self, key: u64) -> bool {      // 既定の FIFO mailbox か
+        matches!(self.kinds.get(&key), None | Some(MailboxKind::Fifo))
+    }
+
+    fn messages(&mut self, key: u64) -> Vec<Envelope> {      // 取り出す順に複製する (取り出して積み直すので FIFO のみ順序が保たれる)
+        let msgs: Vec<Envelope> = match self.map.get_mut(&key) {
+            Some(mailbox) => std::iter::from_fn(|| mailbox.pop()).collect(),
+            None => return Vec::new(),
+        };
+        self.bytes.remove(&key);
+        for env in &msgs {
+            self.push(key, *env, 0);
+        }
+        msgs
+    }
+
+    fn lens(&self) -> Vec<(u64, usize)> {       // 空でない mailbox の (key, 要素数) の一覧
+        self.map.iter().map(|(key, mailbox)| (*key, mailbox.len())).collect()
+    }
+}
+
+/*  thread_id を key とする map 用の hasher: id は乱数 (get_id) なので、値をそのまま hash として使う
+    SipHash は HashDoS 対策のためのもので、外部から key を選べない id には不要
+    u64 以外が書き込まれた場合 (使われないはず) は FNV-1a で混ぜる
+*/
+#[derive(Default)]
+struct IdHasher(u64);
+
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u64(&mut self, id: u64) {
+        self.0 = id;
+    }
+}
+
+type IdMap<V> = HashMap<u64, V, BuildHasherDefault<IdHasher>>;
+type IdSet = HashSet<u64, BuildHasherDefault<IdHasher>>;
+
+// マルチスレッド化する場合には mutex などで保護する必要がある; 簡単のため global 変数を用いる
+static mut CTX_MAIN: Option<Box<Registers>> = None;     // main() のコンテキスト
+static mut UNUSED_STACK: Vec<(*mut u8, Layout, usize)> = Vec::new();    // free() すべきスタック領域へのポインタとレイアウト、ガードページの大きさ (連続して終了しても取りこぼさない)
+static mut CONTEXTS: LinkedList<ContextBox> = LinkedList::new();      // threads queue
+/*  thread id の集合 (None => runtime 停止中): id の払い出し・返却と is_alive / live_actors の参照だけを Mutex で保護
+    thread-safe なのは id の操作だけで、spawn や kill など runtime の他の状態 (CONTEXTS, MESSAGES, ...) は
+    runtime を動かす OS スレッドからしか触れない -> 別の OS スレッドから spawn してはいけない
+*/
+static ID: Mutex<Option<IdSet>> = Mutex::new(None);
+static mut MESSAGES: *mut MappedList = ptr::null_mut();
+static mut WAITING: *mut IdMap<ContextBox> = ptr::null_mut();
+static mut SUSPENDED: *mut HashMap<u64, ContextBox> = ptr::null_mut();     // resume() されるまで実行しない thread
+static mut TICKS: u64 = 0;      // 論理時計: scheduler の tick 数
+static mut TICK_LIMIT: Option<u64> = None;      // spawn_from_main_with_budget の上限
+static mut SHUTTING_DOWN: bool = false;     // shutdown() 後は receive が受信待ちせず None を返す
+static mut CONFIG: RuntimeConfig = RuntimeConfig::new();
+static mut LOCKED_STACKS: *mut HashSet<usize> = ptr::null_mut();     // spawn_mlocked で mlock したスタックの先頭アドレス
+static mut STACK_TOTAL: usize = 0;      // 確保中のスタック領域の合計 (解放待ちの UNUSED_STACK を含む)
+static mut LEAKED_STACK_BYTES: usize = 0;       // ガードページを戻せずに leak させたスタック領域の合計 (session をまたいで数える)
+static mut PLATFORM: &'static dyn Platform = &LinuxPlatform;   // スタック確保とガードページの実装
+static mut CONTEXT_POOL: Vec<ContextBox> = Vec::new();    // 終了した thread の ContextBox を再利用 -> spawn 時の allocator 呼び出しを削減
+const CONTEXT_POOL_MAX: usize = 64;
+
+// run_blocking 用: OS スレッドから単一スレッドのスケジューラへの起床キュー (ここだけは OS スレッド間で共有されるため Mutex で保護)
+static BLOCKING_DONE: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());     // 完了した (thread_id, 結果)
+static BLOCKING_CV: Condvar = Condvar::new();
+static mut BLOCKING_PENDING: usize = 0;     // OS スレッドで実行中の処理の数
+static mut BLOCKING_RESULTS: *mut HashMap<u64, u64> = ptr::null_mut();     // thread_id -> 受け取り待ちの結果
+// runtime の外の OS スレッドから送られた (宛先, message); schedule のたびに lock しないよう有無だけ atomic で示す
+static EXTERNAL: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+static EXTERNAL_PENDING: AtomicBool = AtomicBool::new(false);
+const DEADLOCK_ROUND: Duration = Duration::from_millis(10);
+
+/*  scheduler のデータ構造 (CONTEXTS, MESSAGES, WAITING) を変更中かどうか
+    signal handler などから非同期に呼ばれる処理は、これらを直接触らず RESCHEDULE を立てるだけにする
+    -> 実際の schedule() は次の安全な地点 (safe_point) まで遅延される
+*/
+static mut IN_SCHEDULER: bool = false;
+static RESCHEDULE: AtomicBool = AtomicBool::new(false);     // 遅延された reschedule の要求
+
+// join 用: 終了した thread の終了理由と、終了を待っている thread
+static mut EXITED: *mut HashMap<u64, ExitReason> = ptr::null_mut();
+static mut JOINERS: *mut HashMap<u64, Vec<u64>> = ptr::null_mut();      // 終了を待たれている thread_id -> 待っている thread_id
+
+// barrier id -> Barrier
+static mut BARRIERS: *mut HashMap<u64, Barrier> = ptr::null_mut();
+
+// 受信待ち状態の thread_id -> 待っている理由
+static mut WAIT_REASONS: *mut HashMap<u64, WaitReason> = ptr::null_mut();
+
+// 待ち合わせ: thread_id -> 待っている相手 (join 先, call の宛先) / 待機を打ち切られた thread_id
+static mut WAITING_ON: *mut HashMap<u64, u64> = ptr::null_mut();
+static mut BROKEN: *mut HashSet<u64> = ptr::null_mut();
+
+// リンク: thread_id -> リンクしている thread_id の集合
+static mut LINKS: *mut HashMap<u64, HashSet<u64>> = ptr::null_mut();
+
+// worker pool: pool id -> WorkerPool / worker の thread_id -> 所属する pool id
+static mut NEXT_POOL: u64 = 0;
+static mut POOLS: *mut HashMap<u64, WorkerPool> = ptr::null_mut();
+static mut POOL_OF: *mut HashMap<u64, u64> = ptr::null_mut();
+
+// send_dedup: 宛先の thread_id -> 直近に受け付けた dedup_id (古い順, 最大 dedup_window 個)
+static mut DEDUP_SEEN: *mut HashMap<u64, VecDeque<u64>> = ptr::null_mut();
+
+// 名前付き group: group 名 -> 参加している thread_id (参加順)
+static mut GROUPS: *mut HashMap<String, Vec<u64>> = ptr::null_mut();
+
+// cancel token: token id -> 受信待ちの thread_id / cancel 済みの token id
+static mut NEXT_TOKEN: u64 = 0;
+static mut TOKEN_WAITERS: *mut HashMap<u64, u64> = ptr::null_mut();
+static mut CANCELLED: *mut HashSet<u64> = ptr::null_mut();
+
+// send_tracked: ack_id -> (送信元, message) / 宛先 -> 未 ack の ack_id (送信順) / 受信されないことが確定した ack_id
+static mut NEXT_ACK: u64 = 0;
+static mut UNACKED: *mut HashMap<u64, (u64, u64)> = ptr::null_mut();
+static mut TRACKED: *mut HashMap<u64, Vec<u64>> = ptr::null_mut();
+static mut LOST_ACKS: *mut HashSet<u64> = ptr::null_mut();
+
+// actor ごとのガードページへの fault 回数 (スタックサイズの調整用)
+static mut GUARD_FAULTS: *mut HashMap<u64, u64> = ptr::null_mut();
+
+// MailboxHandle の mailbox の id: actor の thread_id (ID) とは別に管理し、is_alive / live_actors には含めない
+static mut MAILBOX_IDS: *mut HashSet<u64> = ptr::null_mut();
+
+// 受信待ちから起こされた thread_id -> 起こした actor (debug build のみ; receive の診断用)
+#[cfg(debug_assertions)]
+static mut WOKEN_BY: *mut HashMap<u64, u64> = ptr::null_mut();
+
+// Entry 関数のアドレス -> runtime 開始からの spawn 回数 (debug build のみ)
+#[cfg(debug_assertions)]
+static mut SPAWN_COUNTS: *mut HashMap<u64, usize> = ptr::null_mut();
+
+// actor ごとの送受信数 (容量計画用)
+static mut SENT_COUNT: *mut HashMap<u64, u64> = ptr::null_mut();
+static mut RECV_COUNT: *mut HashMap<u64, u64> = ptr::null_mut();
+static mut DROPPED_COUNT: *mut HashMap<u64, u64> = ptr::null_mut();      // 宛先 -> max_mailbox_bytes と Bounded の容量で捨てられた message の数
+
+// correlation id による request/reply
+static mut NEXT_CORR: u64 = 0;
+static mut PENDING_REPLIES: *mut HashMap<u64, u64> = ptr::null_mut();      // corr_id -> reply を待っている thread_id
+static mut REPLIES: *mut HashMap<u64, u64> = ptr::null_mut();              // corr_id -> 受け取り待ちの reply
+
+pub fn set_config(config: RuntimeConfig) {
+    assert!(
+        config.stack_align.is_power_of_two() && config.stack_align.is_multiple_of(unsafe {PLATFORM.page_size()}),
+        "stack_align must be a power of two and a multiple of the page size: {}", config.stack_align
+    );
+    unsafe {
+        CONFIG = config;
+    }
+}
+
+pub fn config() -> RuntimeConfig {
+    unsafe {
+        CONFIG.clone()
+    }
+}
+
+// スタック確保・ガードページの実装を差し替える
+// 確保したスタックは同じ実装で解放しなければならないため、runtime の停止中 (spawn_from_main の外) でのみ呼べる
+pub fn set_platform(platform: &'static dyn Platform) {
+    assert!(ID.lock().unwrap().is_none(), "set_platform must be called while the runtime is not running");
+    unsafe {
+        PLATFORM = platform;
+    }
+}
+
+fn get_id() -> u64 {
+    let mut ids = ID.lock().unwrap();
+    let ids = ids.as_mut().expect("runtime is not running");
+    loop {
+        let rnd = rand::random::<u64>();
+        if !is_mailbox(rnd) && ids.insert(rnd) {    // 未使用の id であれば登録して返す (MailboxHandle の id とも重ならない)
+            return rnd;
+        }
+    }
+}
+
+fn release_id(id: u64) {
+    if let Some(ids) = ID.lock().unwrap().as_mut() {
+        ids.remove(&id);
+    }
+}
+
+// id が生存中の MailboxHandle の mailbox か
+fn is_mailbox(id: u64) -> bool {
+    unsafe {
+        !MAILBOX_IDS.is_null() && (*MAILBOX_IDS).contains(&id)
+    }
+}
+
+// actor が生存中か (実行可能・受信待ち・停止中を含む)
+// 別の OS スレッドからも呼べるが、結果はその時点のもの (直後に spawn / 終了した actor は反映されない)
+pub fn is_alive(id: u64) -> bool {
+    ID.lock().unwrap().as_ref().is_some_and(|ids| ids.contains(&id))
+}
+
+/*  Context を置く領域の所有者 (Box<Context> の代わり): 領域は Platform::alloc_context から確保する
+    -> actor の生成・終了が多い場合に、Platform を差し替えて arena や bump allocator を使える
+    mailbox の要素は MailboxKind::Custom で独自の mailbox 実装 (独自の allocator を使うもの) に差し替える
+    対象は Context の領域だけ: CONTEXTS / WAITING / MESSAGES などの表 (LinkedList の node, HashMap の rehash) は
+    安定版の Rust で collection に allocator を渡す手段 (allocator_api) がないため、常に global allocator を使う
+    allocator の負荷を比べる benchmark も用意していない (解放の対応は test の bump allocator で確かめる)
+*/
+struct ContextBox(ptr::NonNull<Context>);
+
+impl ContextBox {
+    unsafe fn new(ctx: Context) -> Self {
+        let layout = Layout::new::<Context>();
+        let p = PLATFORM.alloc_context(layout) as *mut Context;
+        if p.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        p.write(ctx);
+        ContextBox(ptr::NonNull::new_unchecked(p))
+    }
+}
+
+impl Deref for ContextBox {
+    type Target = Context;
+
+    fn deref(&self) -> &Context {
+        unsafe {self.0.as_ref()}
+    }
+}
+
+impl DerefMut for ContextBox {
+    fn deref_mut(&mut self) -> &mut Context {
+        unsafe {self.0.as_mut()}
+    }
+}
+
+impl Drop for ContextBox {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.0.as_ptr());
+            PLATFORM.dealloc_context(self.0.as_ptr() as *mut u8, Layout::new::<Context>());
+        }
+    }
+}
+
+// pool に ContextBox があれば中身だけ差し替えて再利用する
+// 生成に失敗した場合は thread_id を返却する
+unsafe fn new_context(func: Task, stack_size: usize, thread_id: u64) -> Result<ContextBox, ActorError> {
+    match Context::new(func, stack_size, thread_id) {
+        Ok(ctx) => Ok(box_context(ctx)),
+        Err(e) => {
+            release_id(thread_id);
+            Err(e)
+        }
+    }
+}
+
+unsafe fn box_context(mut ctx: Context) -> ContextBox {
+    #[cfg(debug_assertions)]
+    check_guard_layout(&ctx);
+    ctx.parent = CONTEXTS.front().map(|c| c.thread_id);     // 生成した (実行中の) actor
+    match CONTEXT_POOL.pop() {
+        Some(mut boxed) => {
+            *boxed = ctx;
+            boxed
+        },
+        None => ContextBox::new(ctx),
+    }
+}
+
+// 閾値に達した時に一度だけ警告する
+#[cfg(debug_assertions)]
+unsafe fn count_spawn(func: Entry) {
+    if SPAWN_COUNTS.is_null() {
+        return;
+    }
+    let count = (*SPAWN_COUNTS).entry(func as usize as u64).or_insert(0);
+    *count += 1;
+    if *count == CONFIG.spawn_warn_threshold {
+        eprintln!("warning: entry function {:p} has been spawned {} times; possible runaway spawn loop", func as *const (), *count);
+    }
+}
+
+// spawn 系の関数の最後の処理: spawn_yields なら生成した thread に実行権を譲る
+fn spawned() {
+    if unsafe {CONFIG.spawn_yields} {
+        schedule_from("spawn");
+    }
+}
+
+fn spawn_failed(e: ActorError) -> ! {
+    panic!("spawn failed: {}", e);
+}
+
+/*  actor の中から呼ぶ API の定義を包む: global_runtime では free function として公開し、
+    explicit_runtime のみの場合は green module の中に閉じて、Runtime の同名のメソッドからだけ呼べるようにする
+    (spawn / send / receive は *_impl を共有する形で個別に定義)
+*/
+macro_rules! actor_api {
+    ($(#[$attr:meta])* pub fn $($item:tt)*) => {
+        #[cfg(feature = "global_runtime")]
+        $(#[$attr])*
+        pub fn $($item)*
+
+        #[cfg(not(feature = "global_runtime"))]
+        $(#[$attr])*
+        fn $($item)*
+    };
+    ($(#[$attr:meta])* pub unsafe fn $($item:tt)*) => {
+        #[cfg(feature = "global_runtime")]
+        $(#[$attr])*
+        pub unsafe fn $($item)*
+
+        #[cfg(not(feature = "global_runtime"))]
+        $(#[$attr])*
+        unsafe fn $($item)*
+    };
+}
+
+// runtime を動かす OS スレッドからのみ呼べる (ID 以外の状態は lock で保護されていない)
+#[cfg(feature = "global_runtime")]
+pub fn spawn(func: Entry, stack_size: usize) -> u64 {
+    spawn_impl(func, stack_size)
+}
+
+fn spawn_impl(func: Entry, stack_size: usize) -> u64 {
+    try_spawn(func, stack_size).unwrap_or_else(|e| spawn_failed(e))
+}
+
+// spawn と同じだが、生成の失敗を panic ではなく ActorError で返す
+actor_api! {
+    pub fn try_spawn(func: Entry, stack_size: usize) -> Result<u64, ActorError> {
+        unsafe {
+            let id = get_id();
+            CONTEXTS.push_back(new_context(spawn_entry(func), stack_size, id)?);   // queue の最後尾に新規作成
+            spawned();     // コンテキストスイッチ
+            Ok(id)
+        }
+    }
+}
+
+/*  呼び出し側が確保したメモリ [stack, stack + size) をスタックとして thread を生成する (組込み環境や独自の stack pool 向け)
+    runtime は確保・ガードページの設定・解放のいずれも行わない; total_stack_budget にも数えない
+    safety:
+        stack と size はともに 16 の倍数であること
+        size は RED_ZONE とスタックの使用量の合計より十分に大きいこと (MIN_USABLE_STACK 以上を推奨)
+        領域は thread が終了 (kill を含む) するか runtime が終わるまで有効で、他から読み書きされないこと
+        ガードページが必要なら、呼び出し側が領域の下端に mprotect しておくこと (ない場合のオーバーフローは検出されない)
+*/
+actor_api! {
+    pub unsafe fn spawn_with_stack(func: Entry, stack: *mut u8, size: usize) -> u64 {
+        assert!((stack as usize).is_multiple_of(16) && size.is_multiple_of(16), "spawn_with_stack: stack {:p} and size {} must be 16-byte aligned", stack, size);
+        assert!(size > RED_ZONE, "spawn_with_stack: size {} leaves no usable stack", size);
+        let id = get_id();
+        let layout = Layout::from_size_align(size, 16).unwrap();
+        let mut ctx = Context::on_stack(spawn_entry(func), stack, layout, 0, id);
+        ctx.owns_stack = false;
+        CONTEXTS.push_back(box_context(ctx));
+        spawned();
+        id
+    }
+}
+
+// 同じ関数の thread を n 個まとめて生成し、schedule() は最後に一度だけ行う; id は生成順
+actor_api! {
+    pub fn spawn_many(func: Entry, stack_size: usize, n: usize) -> Vec<u64> {
+        unsafe {
+            let mut ids = Vec::with_capacity(n);
+            for _ in 0..n {
+                let id = get_id();
+                let ctx = new_context(spawn_entry(func), stack_size, id).unwrap_or_else(|e| spawn_failed(e));
+                CONTEXTS.push_back(ctx);
+                ids.push(id);
+            }
+            spawned();
+            ids
+        }
+    }
+}
+
+/*  スタックのガードページを除く使用可能領域を mlock してから thread を生成する (page fault による jitter を避けたい actor 向け)
+    固定は actor の終了時 (スタックの解放時) に解除される
+    mlock に失敗 (RLIMIT_MEMLOCK の超過など) したら生成せず ActorError::MlockFailed
+*/
+actor_api! {
+    pub fn spawn_mlocked(func: Entry, stack_size: usize) -> Result<u64, ActorError> {
+        unsafe {
+            let id = get_id();
+            let ctx = new_context(spawn_entry(func), stack_size, id)?;
+            let usable = ctx.stack.add(ctx.guard_size);
+            if let Err(errno) = PLATFORM.lock(usable, ctx.stack_layout.size() - ctx.guard_size) {
+                free_stack(ctx.stack, ctx.stack_layout, ctx.guard_size);
+                recycle(ctx);
+                release_id(id);
+                return Err(ActorError::MlockFailed(errno));
+            }
+            (*LOCKED_STACKS).insert(ctx.stack as usize);
+            CONTEXTS.push_back(ctx);
+            spawned();
+            Ok(id)
+        }
+    }
+}
+
+// spawn_try の Err を entry_point まで運ぶ unwind の payload
+struct ActorFailure(String);
+
+/*  Result を返す関数を thread として実行する: Ok(()) は ExitReason::Normal(0), Err(e) は ExitReason::Error(format!("{:?}", e))
+    Err は panic とは区別され (panic handler は呼ばれない)、異常終了として join / link / monitor に通知される
+*/
+actor_api! {
+    pub fn spawn_try<F, E>(f: F, stack_size: usize) -> u64
+    where
+        F: FnOnce() -> Result<(), E> + 'static,
+        E: fmt::Debug,
+    {
+        spawn_fn(move || match f() {
+            Ok(()) => 0,
+            Err(e) => panic::resume_unwind(Box::new(ActorFailure(format!("{:?}", e)))),     // panic hook を通さずに unwind
+        }, stack_size)
+    }
+}
+
+// id と mailbox だけ先に用意し、resume() されるまで実行しない thread を生成
+actor_api! {
+    pub fn spawn_suspended(func: Entry, stack_size: usize) -> u64 {
+        unsafe {
+            let id = get_id();
+            let ctx = new_context(spawn_entry(func), stack_size, id).unwrap_or_else(|e| spawn_failed(e));
+            (*SUSPENDED).insert(id, ctx);     // CONTEXTS には入れない
+            id
+        }
+    }
+}
+
+// 停止中の thread を実行可能にする; 停止中でなければ false
+actor_api! {
+    pub fn resume(id: u64) -> bool {
+        unsafe {
+            if let Some(ctx) = (*SUSPENDED).remove(&id) {
+                CONTEXTS.push_back(ctx);
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+
+// 戻り値を actor の結果とする closure を thread として生成
+actor_api! {
+    pub fn spawn_fn<F>(f: F, stack_size: usize) -> u64
+    where
+        F: FnOnce() -> u64 + 'static,
+    {
+        unsafe {
+            let id = get_id();
+            CONTEXTS.push_back(new_context(Box::new(f), stack_size, id).unwrap_or_else(|e| spawn_failed(e)));
+            spawned();
+            id
+        }
+    }
+}
+
+// spawn_fn と同じだが、結果を一度だけ受け取れる handle も返す (handle は他の actor に渡してもよい)
+actor_api! {
+    pub fn spawn_with_result<F>(f: F, stack_size: usize) -> (u64, ResultHandle)
+    where
+        F: FnOnce() -> u64 + 'static,
+    {
+        let id = spawn_fn(f, stack_size);
+        (id, ResultHandle { id })
+    }
+}
+
+pub struct ResultHandle {
+    id: u64,
+}
+
+impl ResultHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // actor の終了まで待ち、正常終了なら結果、それ以外なら終了理由を返す; handle は消費される
+    pub fn get(self) -> Result<u64, ExitReason> {
+        match join(self.id) {
+            ExitReason::Normal(result) => Ok(result),
+            reason => Err(reason),
+        }
+    }
+}
+
+// guard が drop されると actor を kill する (生成した側の scope より長生きさせない補助 actor 用)
+pub struct ActorGuard(u64);
+
+impl ActorGuard {
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+
+    // kill せずに guard を外し、id を返す
+    pub fn detach(self) -> u64 {
+        let id = self.0;
+        std::mem::forget(self);
+        id
+    }
+}
+
+impl Drop for ActorGuard {
+    fn drop(&mut self) {
+        if is_alive(self.0) {   // 既に終了していれば何もしない
+            kill(self.0);
+        }
+    }
+}
+
+actor_api! {
+    pub fn spawn_guarded(func: Entry, stack_size: usize) -> ActorGuard {
+        ActorGuard(spawn_impl(func, stack_size))
+    }
+}
+
+// 起動前に init を mailbox に入れておく -> 最初の receive は (他の send と競合せず) 必ず init を返す
+actor_api! {
+    pub fn spawn_with_msg(func: Entry, stack_size: usize, init: u64) -> u64 {
+        unsafe {
+            let id = get_id();
+            let ctx = new_context(spawn_entry(func), stack_size, id).unwrap_or_else(|e| spawn_failed(e));
+            (*MESSAGES).push_back(id, init);        // id を得た直後で、まだ誰も id を知らないため先頭になる
+            CONTEXTS.push_back(ctx);
+            spawned();
+            id
+        }
+    }
+}
+
+/*  spawn の各オプションをまとめて指定する builder
+    ActorBuilder::new().stack(64 * 1024).priority(5).weight(2).mailbox_cap(16).supervised(SupervisionPolicy::TrapExit).spawn(func)
+    実行 worker の固定は runtime がシングルスレッドのため対応しない
+*/
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+// 生成した actor と spawn した側 (実行中の actor) の関係
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SupervisionPolicy {
+    Link,       // link(spawn した側, 子): どちらかが異常終了すると他方も終了する
+    TrapExit,   // Link に加えて spawn した側を trap_exit にする -> 子の異常終了を message として受け取る
+}
+
+pub struct ActorBuilder {
+    stack_size: usize,
+    priority: u8,
+    weight: u32,
+    trap_exit: bool,
+    suspended: bool,
+    mailbox: MailboxKind,
+    supervision: Option<SupervisionPolicy>,
+}
+
+impl ActorBuilder {
+    pub fn new() -> Self {
+        ActorBuilder {
+            stack_size: DEFAULT_STACK_SIZE,
+            priority: DEFAULT_PRIORITY,
+            weight: 1,
+            trap_exit: false,
+            suspended: false,
+            mailbox: MailboxKind::Fifo,
+            supervision: None,
+        }
+    }
+
+    pub fn stack(mut self, stack_size: usize) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
+
+    pub fn priority(mut self, p: u8) -> Self {
+        assert!(p <= MAX_PRIORITY, "priority must be at most {}: {}", MAX_PRIORITY, p);
+        self.priority = p;
+        self
+    }
+
+    // 実行権を得るたびに、yield_now などで他へ切り替わるまでに続けて使える slice の数 (既定は 1)
+    pub fn weight(mut self, w: u32) -> Self {
+        assert!(w >= 1, "weight must be at least 1");
+        self.weight = w;
+        self
+    }
+
+    // リンク先の異常終了を message として受け取る状態で開始する (trap_exit(true) と同じ)
+    pub fn trap_exit(mut self, on: bool) -> Self {
+        self.trap_exit = on;
+        self
+    }
+
+    // mailbox の格納方式を選ぶ
+    pub fn mailbox(mut self, kind: MailboxKind) -> Self {
+        self.mailbox = kind;
+        self
+    }
+
+    // send_prio の優先度順に message を受け取る mailbox にする (mailbox(MailboxKind::Priority) と同じ)
+    pub fn priority_mailbox(self) -> Self {
+        self.mailbox(MailboxKind::Priority)
+    }
+
+    // mailbox に溜められる message を cap 個までにする (mailbox(MailboxKind::Bounded(cap)) と同じ); 溢れた send は捨てられる
+    pub fn mailbox_cap(self, cap: usize) -> Self {
+        self.mailbox(MailboxKind::Bounded(cap))
+    }
+
+    // spawn した側 (実行中の actor) と子の監視関係を設定する
+    pub fn supervised(mut self, policy: SupervisionPolicy) -> Self {
+        self.supervision = Some(policy);
+        self
+    }
+
+    // resume() されるまで実行しない
+    pub fn suspended(mut self) -> Self {
+        self.suspended = true;
+        self
+    }
+
+    pub fn spawn(self, func: Entry) -> u64 {
+        self.try_spawn(func).unwrap_or_else(|e| spawn_failed(e))
+    }
+
+    pub fn try_spawn(self, func: Entry) -> Result<u64, ActorError> {
+        unsafe {
+            let id = get_id();
+            let mut ctx = new_context(spawn_entry(func), self.stack_size, id)?;
+            ctx.priority = self.priority;
+            ctx.weight = self.weight;
+            ctx.slices_left = self.weight - 1;
+            ctx.trap_exit = self.trap_exit;
+            if !matches!(self.mailbox, MailboxKind::Fifo) {
+                (*MESSAGES).set_kind(id, self.mailbox);
+            }
+            if let (Some(policy), Some(parent)) = (self.supervision, ctx.parent) {
+                link(parent, id);
+                if policy == SupervisionPolicy::TrapExit {
+                    running("ActorBuilder::supervised").trap_exit = true;     // parent は実行中の actor
+                }
+            }
+
+            if self.suspended {
+                (*SUSPENDED).insert(id, ctx);     // spawn_suspended と同じ: CONTEXTS には入れない
+            } else {
+                CONTEXTS.push_back(ctx);
+                spawned();
+            }
+            Ok(id)
+        }
+    }
+}
+
+impl Default for ActorBuilder {
+    fn default() -> Self {
+        ActorBuilder::new()
+    }
+}
+
+/*  async コードから actor の終了を await するための Future
+    executor は別の OS スレッドで動くため、完了通知は Mutex で保護した COMPLETIONS を経由する
+    actor の終了時に結果を書き込み、登録されている Waker を起こす
+    Output は actor の結果 (entry の返り値); panic や kill で終了した場合は poll が終了理由とともに panic する
+*/
+struct Completion {
+    result: Option<ExitReason>,
+    waker: Option<Waker>,
+}
+
+static COMPLETIONS: Mutex<BTreeMap<u64, Completion>> = Mutex::new(BTreeMap::new());
+
+pub struct AsyncJoinHandle {
+    id: u64,
+}
+
+impl AsyncJoinHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Future for AsyncJoinHandle {
+    type Output = u64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<u64> {
+        let mut completions = COMPLETIONS.lock().unwrap();
+        let slot = completions.get_mut(&self.id).expect("AsyncJoinHandle polled after completion");
+        match slot.result.take() {
+            Some(reason) => {
+                completions.remove(&self.id);
+                drop(completions);      // panic する前に lock を解放 (poison させない)
+                match reason {
+                    ExitReason::Normal(result) => Poll::Ready(result),
+                    reason => panic!("AsyncJoinHandle: actor {} did not return normally: {:?}", self.id, reason),
+                }
+            },
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// await されずに drop された handle の slot を削除する (終了していない actor の結果は捨てられる)
+impl Drop for AsyncJoinHandle {
+    fn drop(&mut self) {
+        COMPLETIONS.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+    }
+}
+
+// spawn_fn と同じだが、終了を await できる AsyncJoinHandle も返す
+actor_api! {
+    pub fn spawn_async<F>(f: F, stack_size: usize) -> (u64, AsyncJoinHandle)
+    where
+        F: FnOnce() -> u64 + 'static,
+    {
+        unsafe {
+            let id = get_id();
+            // 生成直後に終了しても取りこぼさないよう、CONTEXTS に積む前に登録
+            COMPLETIONS.lock().unwrap().insert(id, Completion { result: None, waker: None });
+            let ctx = match new_context(Box::new(f), stack_size, id) {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    COMPLETIONS.lock().unwrap().remove(&id);
+                    spawn_failed(e);
+                }
+            };
+            CONTEXTS.push_back(ctx);
+            spawned();
+            (id, AsyncJoinHandle { id })
+        }
+    }
+}
+
+// actor の終了を COMPLETIONS に記録し、await している側を起こす
+fn complete(id: u64, reason: ExitReason) {
+    let mut completions = COMPLETIONS.lock().unwrap();
+    if let Some(slot) = completions.get_mut(&id) {
+        slot.result = Some(reason);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// 実行可能・受信待ち・停止中のいずれかから Context を取り除く
+unsafe fn take_context(id: u64) -> Option<ContextBox> {
+    if let Some(pos) = CONTEXTS.iter().position(|c| c.thread_id == id) {
+        let mut rest = CONTEXTS.split_off(pos);
+        let ctx = rest.pop_front();
+        CONTEXTS.append(&mut rest);
+        return ctx;
+    }
+    if let Some(ctx) = (*WAITING).remove(&id) {
+        return Some(ctx);
+    }
+    (*SUSPENDED).remove(&id)
+}
+
+// 終了理由を記録し、join している thread と AsyncJoinHandle を起こす
+unsafe fn record_exit(id: u64, reason: ExitReason) {
+    (*WAITING_ON).remove(&id);
+    (*WAIT_REASONS).remove(&id);
+    (*BROKEN).remove(&id);
+    (*DEDUP_SEEN).remove(&id);
+    break_barriers(id);
+    if let Some(ack_ids) = (*TRACKED).remove(&id) {     // 終了した actor の mailbox に残った追跡中の message は受信されない
+        for ack_id in ack_ids {
+            fail_ack(ack_id);
+        }
+    }
+    complete(id, reason.clone());
+    (*EXITED).insert(id, reason);
+    if let Some(joiners) = (*JOINERS).remove(&id) {
+        for joiner in joiners {
+            wake(joiner);
+        }
+    }
+}
+
+// 待機中に kill された actor を、待っていた相手 (join 先, cancel token, reply) の登録から外す
+// -> 後で終了済みの id を起こしたり、誰も受け取らない reply を残したりしない
+unsafe fn forget_waiter(id: u64) {
+    for joiners in (*JOINERS).values_mut() {
+        joiners.retain(|&joiner| joiner != id);
+    }
+    (*JOINERS).retain(|_, joiners| !joiners.is_empty());
+    (*TOKEN_WAITERS).retain(|_, waiter| *waiter != id);
+    (*PENDING_REPLIES).retain(|_, caller| *caller != id);
+}
+
+// actor を強制終了させる; 該当する actor がいなければ false
+// 終了させた actor のスタック上のローカル変数は drop されない
+actor_api! {
+    pub fn kill(id: u64) -> bool {
+        unsafe {
+            if CONTEXTS.front().map(|c| c.thread_id) == Some(id) {     // 自分自身
+                terminate(ExitReason::Killed);
+            }
+
+            let ctx = match take_context(id) {
+                Some(ctx) => ctx,
+                None => return false,
+            };
+            release_id(id);
+            (*MESSAGES).remove(id);
+            forget_waiter(id);
+            if ctx.owns_stack {
+                free_stack(ctx.stack, ctx.stack_layout, ctx.guard_size);     // 実行中のスタックではないのですぐに解放できる
+            }
+            recycle(ctx);
+            record_exit(id, ExitReason::Killed);
+            propagate_exit(id, &ExitReason::Killed);
+            true
+        }
+    }
+}
+
+// a と b を双方向にリンク: どちらかが異常終了 (panic / kill) するともう一方も終了する
+actor_api! {
+    pub fn link(a: u64, b: u64) {
+        unsafe {
+            if a == b {
+                return;
+            }
+            (*LINKS).entry(a).or_default().insert(b);
+            (*LINKS).entry(b).or_default().insert(a);
+        }
+    }
+}
+
+actor_api! {
+    pub fn unlink(a: u64, b: u64) {
+        unsafe {
+            if let Some(peers) = (*LINKS).get_mut(&a) {
+                peers.remove(&b);
+            }
+            if let Some(peers) = (*LINKS).get_mut(&b) {
+                peers.remove(&a);
+            }
+        }
+    }
+}
+
+// 有効にすると、リンク先の異常終了で自分が終了する代わりに、終了した actor の id を message として受け取る
+actor_api! {
+    pub fn trap_exit(enabled: bool) {
+        unsafe {
+            running("trap_exit").trap_exit = enabled;
+        }
+    }
+}
+
+/*  id の終了をリンク先へ伝播
+    リンクはたどった時点で削除するため、リンクが循環していても無限に再帰しない
+    異常終了の場合は連鎖して終了する actor をすべて求めてから kill し、実行中の actor 自身が含まれていれば最後に終了する
+*/
+unsafe fn propagate_exit(id: u64, reason: &ExitReason) {
+    let abnormal = !matches!(reason, ExitReason::Normal(_));
+    let running = CONTEXTS.front().map(|c| c.thread_id);
+
+    let mut queue = vec![id];
+    let mut doomed = Vec::new();
+    while let Some(dead) = queue.pop() {
+        let peers = (*LINKS).remove(&dead).unwrap_or_default();
+        for peer in peers {
+            if let Some(set) = (*LINKS).get_mut(&peer) {
+                set.remove(&dead);
+            }
+            if !abnormal || doomed.contains(&peer) {
+                continue;   // 正常終了ならリンクを外すだけ
+            }
+
+            if find_context(peer).is_some_and(|c| c.trap_exit) {
+                (*MESSAGES).push_back(peer, dead);      // trap_exit 中: 終了した actor の id を通知
+                wake(peer);
+            } else {
+                doomed.push(peer);
+                queue.push(peer);
+            }
+        }
+    }
+
+    let mut kill_self = false;
+    for peer in doomed {
+        if Some(peer) == running && peer != id {
+            kill_self = true;
+        } else {
+            kill(peer);
+        }
+    }
+    if kill_self {
+        terminate(ExitReason::Killed);
+    }
+}
+
+// actor の終了を待ち、終了理由を返す
+actor_api! {
+    pub fn join(id: u64) -> ExitReason {
+        try_join(id).unwrap_or_else(|e| panic!("join: {}", e))
+    }
+}
+
+// join と同じだが、break_wait_cycles により待機を打ち切られた場合は Err(WaitCycleBroken)
+actor_api! {
+    pub fn try_join(id: u64) -> Result<ExitReason, ActorError> {
+        unsafe {
+            let key = current_id();
+            if key == id {
+                panic!("join: an actor cannot join itself");
+            }
+
+            loop {
+                if let Some(reason) = (*EXITED).get(&id) {
+                    (*WAITING_ON).remove(&key);
+                    return Ok(reason.clone());
+                }
+                if !is_alive(id) {
+                    panic!("join: unknown actor {}", id);
+                }
+                if (*BROKEN).remove(&key) {
+                    (*WAITING_ON).remove(&key);
+                    if let Some(joiners) = (*JOINERS).get_mut(&id) {
+                        joiners.retain(|j| *j != key);
+                    }
+                    return Err(ActorError::WaitCycleBroken);
+                }
+
+                let joiners = (*JOINERS).entry(id).or_default();
+                if !joiners.contains(&key) {
+                    joiners.push(key);
+                }
+                (*WAITING_ON).insert(key, id);
+                park_for(WaitReason::Join(id));     // 対象の終了以外で起床しても再び待つ
+            }
+        }
+    }
+}
+
+/*  0..n を chunk ごとに分けて actor で処理し、全ての actor を join してから戻る (fork/join)
+    協調的マルチタスクなので並列ではなく interleaving: 各 chunk は f の中で yield しない限り続けて実行される
+    f は Rc で子と共有する: 呼び出し元が join の途中で kill されても、最後の子が終わるまで f は解放されない
+    chunk のいずれかが正常終了しなければ、全ての chunk の終了を待ってから panic する
+*/
+actor_api! {
+    pub fn parallel_for<F: Fn(usize) + Sync + 'static>(n: usize, chunk: usize, f: F) {
+        assert!(chunk > 0, "parallel_for: chunk must be at least 1");
+        let f = Rc::new(f);
+
+        let ids: Vec<u64> = (0..n).step_by(chunk).map(|start| {
+            let end = (start + chunk).min(n);
+            let f = Rc::clone(&f);
+            spawn_fn(move || {
+                for i in start..end {
+                    f(i);
+                }
+                0
+            }, DEFAULT_STACK_SIZE)
+        }).collect();
+
+        let failed = ids.into_iter().filter(|id| join(*id) != ExitReason::Normal(0)).count();
+        if failed > 0 {
+            panic!("parallel_for: {} chunk(s) did not complete", failed);
+        }
+    }
+}
+
+// join と同じだが、rounds 回 yield しても終了しなければ諦めて None
+// 待っている間は JOINERS に登録しない (yield で待つ) ので、諦めた後に掃除するものはない
+actor_api! {
+    pub fn join_timeout(id: u64, rounds: u64) -> Option<ExitReason> {
+        unsafe {
+            if current_id() == id {
+                panic!("join: an actor cannot join itself");
+            }
+            for _ in 0..rounds {
+                if let Some(reason) = (*EXITED).get(&id) {
+                    return Some(reason.clone());
+                }
+                yield_now();
+            }
+            (*EXITED).get(&id).cloned()
+        }
+    }
+}
+
+/*  WAITING_ON をたどって待ち合わせの循環を探し、待機を打ち切る actor を返す
+    key (実行中の actor) が循環に含まれればそれを、そうでなければ循環中の最小の id を選ぶ
+    各 actor の待ち相手は高々一つなので、各始点から先をたどるだけでよい
+*/
+unsafe fn find_wait_cycle(key: u64) -> Option<u64> {
+    let mut starts: Vec<u64> = (*WAITING_ON).keys().copied().collect();
+    starts.sort();
+    for start in starts {
+        let mut path = Vec::new();
+        let mut cur = start;
+        while let Some(&next) = (*WAITING_ON).get(&cur) {
+            if let Some(pos) = path.iter().position(|id| *id == cur) {
+                let cycle: &[u64] = &path[pos..];
+                return Some(if cycle.contains(&key) { key } else { *cycle.iter().min().unwrap() });
+            }
+            path.push(cur);
+            cur = next;
+        }
+    }
+    None
+}
+
+// 受信待ち状態 (WAITING) の actor が何を待っているか
+#[derive(Clone, Debug, PartialEq)]
+pub enum WaitReason {
+    Message,        // receive 系: mailbox への message
+    Join(u64),      // join: 対象の actor の終了
+    Reply(u64),     // call_with_correlation: 宛先からの reply
+    Ack(u64),       // wait_ack: ack_id の到達確認
+    Blocking,       // run_blocking: OS スレッドでの処理の完了
+    Barrier(u64),   // barrier: 全参加者の到着
+    Park,           // 理由が記録されていない park
+}
+
+// 実行可能な actor の id を CONTEXTS の順に (先頭が実行中の actor)
+pub fn queue_ids() -> Vec<u64> {
+    unsafe {
+        CONTEXTS.iter().map(|ctx| ctx.thread_id).collect()
+    }
+}
+
+/*  呼び出し元の CONTEXTS 内での位置: 実行中の actor は常に先頭にいるため、常に 0 を返す
+    queue_ids()[queue_position()] == current_id() が成り立つ; 負荷の目安には runnable_others を使う
+*/
+actor_api! {
+    pub fn queue_position() -> usize {
+        unsafe {
+            let me = running("queue_position").thread_id;
+            CONTEXTS.iter().position(|ctx| ctx.thread_id == me).unwrap()
+        }
+    }
+}
+
+/*  呼び出し元以外の実行可能な actor の数 (負荷の目安)
+    実行中の actor は常に CONTEXTS の先頭にあるため、CONTEXTS 内での位置 (queue_position) は常に 0 で目安にならない
+    そのため位置ではなく、yield した後に先に実行される actor の数 = queue_ids().len() - 1 を返す
+    優先度の高い actor がいれば、それより早く順番が回ってくることはない
+*/
+actor_api! {
+    pub fn runnable_others() -> usize {
+        unsafe {
+            running("runnable_others");
+            CONTEXTS.len() - 1
+        }
+    }
+}
+
+// 受信待ち状態の actor の id (昇順)
+pub fn waiting_ids() -> Vec<u64> {
+    unsafe {
+        if WAITING.is_null() {
+            return Vec::new();
+        }
+        let mut ids: Vec<u64> = (*WAITING).keys().copied().collect();
+        ids.sort();
+        ids
+    }
+}
+
+// 受信待ち状態の actor と待っている理由 (id の昇順); 監視や deadlock の解析用
+pub fn waiting_detail() -> Vec<(u64, WaitReason)> {
+    unsafe {
+        waiting_ids().into_iter()
+            .map(|id| (id, (*WAIT_REASONS).get(&id).cloned().unwrap_or(WaitReason::Park)))
+            .collect()
+    }
+}
+
+// 生存中の全 actor の id (実行可能・受信待ち・停止中を含む) のスナップショット
+// is_alive と同様に別の OS スレッドからも呼べる (ID の lock だけを取る)
+pub fn live_actors() -> Vec<u64> {
+    // lock を取ったまま一度に複製 -> 一貫したスナップショット
+    ID.lock().unwrap().as_ref().map_or(Vec::new(), |ids| ids.iter().copied().collect())
+}
+
+// id に対応する Context を実行可能・受信待ち・停止中のいずれかから探す; runtime の外では None
+unsafe fn find_context(id: u64) -> Option<&'static Context> {
+    if WAITING.is_null() || SUSPENDED.is_null() {
+        return None;
+    }
+    if let Some(ctx) = CONTEXTS.iter().find(|c| c.thread_id == id) {
+        return Some(&**ctx);
+    }
+    if let Some(ctx) = (*WAITING).get(&id) {
+        return Some(&**ctx);
+    }
+    (*SUSPENDED).get(&id).map(|c| &**c)
+}
+
+// 保存されたレジスタの複製 (debug_internals): フィールドの意味は Registers と同じ
+#[cfg(feature = "debug_internals")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SavedRegs {
+    pub d: [u64; 8],        // d8 ~ d15
+    pub x: [u64; 10],       // x19 ~ x28
+    pub x30: u64,           // 再開するアドレス
+    pub sp: u64,
+}
+
+/*  実行中でない actor (実行待ち・受信待ち・停止中) の保存されたレジスタ
+    実行中の actor のレジスタは保存されていない (CPU 上にある) ため None
+*/
+#[cfg(feature = "debug_internals")]
+pub fn saved_regs(id: u64) -> Option<SavedRegs> {
+    unsafe {
+        if CONTEXTS.front().map(|c| c.thread_id) == Some(id) {
+            return None;
+        }
+        find_context(id).map(|ctx| {
+            let r = &ctx.regs;
+            SavedRegs {
+                d: [r.d8, r.d9, r.d10, r.d11, r.d12, r.d13, r.d14, r.d15],
+                x: [r.x19, r.x20, r.x21, r.x22, r.x23, r.x24, r.x25, r.x26, r.x27, r.x28],
+                x30: r.x30,
+                sp: r.sp,
+            }
+        })
+    }
+}
+
+/*  actor がコンテキストスイッチで実行権を得た回数 (終了済み・不明なら 0)
+    schedule / send / receive などで他の actor から切り替わるごとに数える; 切り替えずに実行を続けた場合は数えない
+*/
+pub fn run_count(id: u64) -> u64 {
+    unsafe {
+        find_context(id).map_or(0, |ctx| ctx.run_count)
+    }
+}
+
+// actor が生成されてから経過した tick 数 (論理時計); 終了済み・不明なら None
+// thread_id は乱数で生成順を表さないため、actor の新旧の比較にはこちらを使う
+pub fn actor_age(id: u64) -> Option<u64> {
+    unsafe {
+        find_context(id).map(|ctx| TICKS - ctx.created)
+    }
+}
+
+// actor を spawn した actor; root または actor が終了済み・不明なら None
+pub fn parent_of(id: u64) -> Option<u64> {
+    unsafe {
+        find_context(id).and_then(|ctx| ctx.parent)
+    }
+}
+
+/*  root と、parent をたどると root に行き着く全ての actor を kill する
+    子孫は生存中の actor の parent から求める (途中の actor が終了済みなら、その先の子孫には届かない)
+    呼び出し元が部分木に含まれる場合は、他を全て kill してから最後に自分を終了させる
+*/
+actor_api! {
+    pub fn kill_tree(root: u64) {
+        unsafe {
+            let mut all: Vec<(u64, Option<u64>)> = CONTEXTS.iter().map(|c| (c.thread_id, c.parent)).collect();
+            all.extend((*WAITING).values().map(|c| (c.thread_id, c.parent)));
+            all.extend((*SUSPENDED).values().map(|c| (c.thread_id, c.parent)));
+
+            let mut subtree = vec![root];
+            let mut i = 0;
+            while i < subtree.len() {
+                let id = subtree[i];
+                subtree.extend(all.iter().filter(|(_, parent)| *parent == Some(id)).map(|(child, _)| *child));
+                i += 1;
+            }
+
+            let me = current_id();
+            for id in subtree.iter().copied().filter(|id| *id != me) {
+                kill(id);       // link による連鎖で既に終了していれば何もしない
+            }
+            if subtree.contains(&me) {
+                kill(me);       // 戻らない
+            }
+        }
+    }
+}
+
+/*  runtime が確保しているスタック領域の合計バイト数 (ガードページ込み): total_stack_budget との差が残りの余裕
+    生存中の actor のスタックと、終了して解放待ち (UNUSED_STACK) のスタックを含む
+    CONTEXT_POOL は ContextBox だけを再利用しスタックは保持しないため、pool の分は 0
+    spawn_with_stack に渡された呼び出し側のスタックは含まない
+*/
+pub fn total_stack_bytes() -> usize {
+    unsafe {STACK_TOTAL}
+}
+
+// ガードページの保護を解除できずに leak させたスタック領域の合計バイト数 (total_stack_bytes には含まれない)
+// プロセスが終わるまで戻らないため、runtime を作り直しても 0 に戻さない
+pub fn leaked_stack_bytes() -> usize {
+    unsafe {LEAKED_STACK_BYTES}
+}
+
+// actor の使用可能なスタック領域 (low, high) (ガードページを除く); デバッガや unwinder 用
+pub fn stack_range(id: u64) -> Option<(usize, usize)> {
+    unsafe {
+        find_context(id).map(|ctx| {
+            let low = ctx.stack as usize + ctx.guard_size;      // 先頭はガードページ
+            let high = ctx.stack as usize + ctx.stack_layout.size();
+            (low, high)
+        })
+    }
+}
+
+/*  ガードページがスタックの伸びる側 (低位アドレス) にあることの検査
+    AArch64 ではスタックは sp (最上位) から低位へ伸びるため、ガードページは確保領域の先頭に置く必要がある
+    - sp は使用可能領域 (stack + guard_size, stack + size] の中にある
+    - /proc/self/maps 上で、ガードページはアクセス不可 (---) で、使用可能領域の先頭は読み書き可能
+    backend を追加したときに「ガードページが逆側にある」退行を検出するためのもの
+*/
+pub fn assert_guard_correct(id: u64) {
+    unsafe {
+        let ctx = find_context(id).unwrap_or_else(|| panic!("assert_guard_correct: unknown actor {}", id));
+        check_guard_layout(ctx);
+
+        if ctx.guard_size == 0 {
+            return;
+        }
+        let guard = ctx.stack as usize;
+        let usable = guard + ctx.guard_size;
+        let maps = std::fs::read_to_string("/proc/self/maps").expect("assert_guard_correct: /proc/self/maps is not readable");
+        let perms = |addr: usize| -> Option<String> {
+            maps.lines().find_map(|line| {
+                let mut fields = line.split_whitespace();
+                let (low, high) = fields.next()?.split_once('-')?;
+                let (low, high) = (usize::from_str_radix(low, 16).ok()?, usize::from_str_radix(high, 16).ok()?);
+                if low <= addr && addr < high { fields.next().map(|p| p.to_string()) } else { None }
+            })
+        };
+        let guard_perms = perms(guard).expect("assert_guard_correct: guard page is not mapped");
+        let last_guard_perms = perms(usable - 1).expect("assert_guard_correct: guard page is not mapped");
+        let usable_perms = perms(usable).expect("assert_guard_correct: usable stack is not mapped");
+        assert!(guard_perms.starts_with("---") && last_guard_perms.starts_with("---"),
+            "actor {}: guard page {:#x}..{:#x} is accessible ({})", id, guard, usable, guard_perms);
+        assert!(usable_perms.starts_with("rw"),
+            "actor {}: first usable stack byte {:#x} is not writable ({})", id, usable, usable_perms);
+    }
+}
+
+// ガードページと sp の位置関係だけの検査 (debug build では生成のたびに行う)
+fn check_guard_layout(ctx: &Context) {
+    let low = ctx.stack as usize + ctx.guard_size;
+    let high = ctx.stack as usize + ctx.stack_layout.size();
+    let sp = ctx.regs.sp as usize;
+    assert!(low < sp && sp <= high,
+        "sp {:#x} is outside the usable stack {:#x}..={:#x}: guard page is on the wrong side", sp, low, high);
+}
+
+/*  実行可能な actor の一覧 (読み取り専用): Context は移動しない
+    スタックやレジスタは直列化できないため含めず、id と属性だけを複製する; 診断や将来の永続化用
+*/
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ActorSnapshot {
+    pub id: u64,
+    pub priority: u8,
+    pub mailbox_depth: usize,   // 未受信の message 数
+    pub stack_size: usize,      // ガードページを含む
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RuntimeSnapshot {
+    pub runnable: Vec<ActorSnapshot>,   // CONTEXTS の順 (先頭が実行中)
+    pub ticks: u64,
+}
+
+pub fn snapshot() -> RuntimeSnapshot {
+    unsafe {
+        if CTX_MAIN.is_none() {
+            return RuntimeSnapshot { runnable: Vec::new(), ticks: TICKS };
+        }
+        let runnable = CONTEXTS.iter().map(|ctx| ActorSnapshot {
+            id: ctx.thread_id,
+            priority: ctx.priority,
+            mailbox_depth: (*MESSAGES).len(ctx.thread_id),
+            stack_size: ctx.stack_layout.size(),
+        }).collect();
+        RuntimeSnapshot { runnable, ticks: TICKS }
+    }
+}
+
+/*  runtime 全体の状態を人が読める形で出力 (panic hook などからの診断用)
+    各項目は DUMP_LIMIT 件までに制限し、出力の大きさを抑える
+*/
+const DUMP_LIMIT: usize = 32;
+
+pub fn dump_state() -> String {
+    let mut out = String::new();
+    unsafe {
+        if CTX_MAIN.is_none() {
+            out.push_str("runtime is not running\n");
+            return out;
+        }
+
+        let _ = writeln!(out, "runnable: {}", CONTEXTS.len());
+        for (pos, ctx) in CONTEXTS.iter().enumerate().take(DUMP_LIMIT) {
+            match ctx.parent {
+                Some(parent) => { let _ = writeln!(out, "    [{}] {} (priority {}, parent {})", pos, ctx.thread_id, ctx.priority, parent); },
+                None => { let _ = writeln!(out, "    [{}] {} (priority {})", pos, ctx.thread_id, ctx.priority); },
+            }
+        }
+
+        let _ = writeln!(out, "waiting: {}", (*WAITING).len());
+        for id in (*WAITING).keys().take(DUMP_LIMIT) {
+            // join で待っている場合は対象を表示
+            match (*JOINERS).iter().find(|(_, joiners)| joiners.contains(id)) {
+                Some((target, _)) => { let _ = writeln!(out, "    {} (joining {})", id, target); },
+                None => { let _ = writeln!(out, "    {}", id); },
+            }
+        }
+
+        let _ = writeln!(out, "suspended: {}", (*SUSPENDED).len());
+        for id in (*SUSPENDED).keys().take(DUMP_LIMIT) {
+            let _ = writeln!(out, "    {}", id);
+        }
+
+        let mailboxes = (*MESSAGES).lens();
+        let total: usize = mailboxes.iter().map(|(_, n)| n).sum();
+        let _ = writeln!(out, "mailboxes: {} ({} message(s) queued)", mailboxes.len(), total);
+        for (id, n) in mailboxes.iter().take(DUMP_LIMIT) {
+            let _ = writeln!(out, "    {}: {}", id, n);
+        }
+
+        let _ = writeln!(out, "unused stacks: {}", UNUSED_STACK.len());
+        for (stack, layout, _) in UNUSED_STACK.iter().take(DUMP_LIMIT) {
+            let _ = writeln!(out, "    {:p} ({} bytes)", *stack, layout.size());
+        }
+
+        let _ = writeln!(out, "live actors: {}, blocking: {}", live_actors().len(), BLOCKING_PENDING);
+    }
+    out
+}
+
+// main() から一度だけ呼ばれ、グローバル変数の初期化と解放を行う
+pub fn spawn_from_main(func: Entry, stack_size: usize) {
+    run_main(vec![(spawn_entry(func), stack_size)], None);
+}
+
+/*  runtime を初期化し、f を root actor として実行して後処理まで行う; f の返り値を返す
+    f の中では spawn / send / receive をそのまま呼べる (spawn_from_main に渡す Entry 関数を別に書かなくてよい)
+    spawn_from_main と同様、f とその子孫がすべて終了してから戻る; root のスタックは DEFAULT_STACK_SIZE
+*/
+pub fn with_runtime<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + 'static,
+    R: 'static,
+{
+    let result = Rc::new(Cell::new(None));
+    let slot = result.clone();
+    let root: Task = Box::new(move || {
+        slot.set(Some(f()));
+        0
+    });
+    run_main(vec![(root, DEFAULT_STACK_SIZE)], None);
+    result.take().expect("with_runtime: the root closure did not finish (panicked or was killed)")
+}
+
+// spawn_from_main と同じだが、複数の root actor を起動し、全ての root (とその子孫) が終了してから main() に戻る
+// root は与えた順に CONTEXTS に並び、先頭から実行される
+pub fn run_all(roots: &[(Entry, usize)]) {
+    assert!(!roots.is_empty(), "run_all: at least one root actor is required");
+    run_main(roots.iter().map(|&(func, stack_size)| (spawn_entry(func), stack_size)).collect(), None);
+}
+
+/*  spawn_from_main と同じだが、scheduler の tick (schedule / 受信待ちの回数) が max_ticks に達したら
+    残っている actor をすべて破棄して main() に戻り、後処理を行う
+    -> 終了しない actor がいても runtime を確実に片付けられる (協調的マルチタスクなので、actor が yield しない限り tick は進まない)
+*/
+pub fn spawn_from_main_with_budget(func: Entry, stack_size: usize, max_ticks: u64) {
+    run_main(vec![(spawn_entry(func), stack_size)], Some(max_ticks));
+}
+
+fn run_main(roots: Vec<(Task, usize)>, max_ticks: Option<u64>) {
+    unsafe {
+        if let Some(_) = &CTX_MAIN {
+            panic!("spawn_from_main is called twice");
+        }
+
+        install_overflow_handler();     // ガードページへのアクセスを StackOverflow として回収する
+
+        // main() 関数用のコンテキストを生成
+        CTX_MAIN = Some(Box::new(Registers::new(0, 0)));      // set_context で上書きされるため初期値は使われない
+        init(max_ticks);
+        /*  CONTEXTS の初期化: roots の消費は set_context より前に済ませる
+            set_context から 2 度目に戻った時、compiler は roots を未消費とみなして再び drop する (二重解放) ため
+        */
+        for (task, stack_size) in roots {
+            let mut ctx = new_context(task, stack_size, get_id()).unwrap_or_else(|e| spawn_failed(e));
+            ctx.parent = None;      // main() から起動された root (CONTEXTS の先頭は別の root)
+            CONTEXTS.push_back(ctx);
+        }
+        if let Some(ctx) = &mut CTX_MAIN {
+            // root の thread を起動
+            if set_context(&mut **ctx as *mut Registers) == 0 {     // main() のコンテキスト保存
+                let first = CONTEXTS.front().unwrap();
+                switch_context(first.get_regs());       // func 実行
+            }   // func() からリターンして main() に戻ってきた
+        }
+        teardown();
+    }
+}
+
+// global 変数の初期化: session ごとの map は heap に確保し、teardown() で解放する
+unsafe fn init(max_ticks: Option<u64>) {
+    MESSAGES = Box::into_raw(Box::new(MappedList::new()));
+    WAITING = Box::into_raw(Box::new(IdMap::default()));
+    SUSPENDED = Box::into_raw(Box::new(HashMap::new()));
+    BLOCKING_RESULTS = Box::into_raw(Box::new(HashMap::new()));
+    EXITED = Box::into_raw(Box::new(HashMap::new()));
+    JOINERS = Box::into_raw(Box::new(HashMap::new()));
+    LINKS = Box::into_raw(Box::new(HashMap::new()));
+    TOKEN_WAITERS = Box::into_raw(Box::new(HashMap::new()));
+    CANCELLED = Box::into_raw(Box::new(HashSet::new()));
+    UNACKED = Box::into_raw(Box::new(HashMap::new()));
+    TRACKED = Box::into_raw(Box::new(HashMap::new()));
+    LOST_ACKS = Box::into_raw(Box::new(HashSet::new()));
+    POOLS = Box::into_raw(Box::new(HashMap::new()));
+    POOL_OF = Box::into_raw(Box::new(HashMap::new()));
+    GROUPS = Box::into_raw(Box::new(HashMap::new()));
+    LOCKED_STACKS = Box::into_raw(Box::new(HashSet::new()));
+    DEDUP_SEEN = Box::into_raw(Box::new(HashMap::new()));
+    WAITING_ON = Box::into_raw(Box::new(HashMap::new()));
+    WAIT_REASONS = Box::into_raw(Box::new(HashMap::new()));
+    BARRIERS = Box::into_raw(Box::new(HashMap::new()));
+    BROKEN = Box::into_raw(Box::new(HashSet::new()));
+    GUARD_FAULTS = Box::into_raw(Box::new(HashMap::new()));
+    MAILBOX_IDS = Box::into_raw(Box::new(HashSet::new()));
+    #[cfg(debug_assertions)]
+    {
+        SPAWN_COUNTS = Box::into_raw(Box::new(HashMap::new()));
+        WOKEN_BY = Box::into_raw(Box::new(HashMap::new()));
+    }
+    SENT_COUNT = Box::into_raw(Box::new(HashMap::new()));
+    RECV_COUNT = Box::into_raw(Box::new(HashMap::new()));
+    DROPPED_COUNT = Box::into_raw(Box::new(HashMap::new()));
+    PENDING_REPLIES = Box::into_raw(Box::new(HashMap::new()));
+    REPLIES = Box::into_raw(Box::new(HashMap::new()));
+    *ID.lock().unwrap() = Some(IdSet::default());
+    TICKS = 0;
+    TICK_LIMIT = max_ticks;
+    #[cfg(all(test, target_arch = "aarch64"))]
+    LIVE_STACK_ALLOCS.store(0, Ordering::SeqCst);       // session ごとに数える (前の session で意図的に leak させたスタックは数えない)
+}
+
+/*  後処理: 全ての global 変数を初期状態 (null, 空, 0) に戻す
+    global 変数を追加した場合はここでのリセットも忘れずに追加すること
+*/
+unsafe fn teardown() {
+    let ids = ID.lock().unwrap().as_ref().map_or(0, |ids| ids.len());
+    rm_unused_stack();      // 不要なスタック解放
+    let contexts = free_remaining();        // tick 上限で打ち切った場合に残っている actor を破棄
+    record_session(ids, contexts);
+    warn_undrained(&*MESSAGES);  // 処理されずに捨てられる message があれば警告
+    debug_assert!(STACK_TOTAL == 0, "{} bytes of stack were not freed at teardown", STACK_TOTAL);
+    restore_overflow_handler();
+
+    CTX_MAIN = None;
+    CONTEXTS.clear();
+    CONTEXT_POOL.clear();
+    TICKS = 0;
+    TICK_LIMIT = None;
+    SHUTTING_DOWN = false;
+    NEXT_POOL = 0;
+    NEXT_TOKEN = 0;
+    NEXT_ACK = 0;
+    NEXT_CORR = 0;
+    IN_SCHEDULER = false;
+    RESCHEDULE.store(false, Ordering::SeqCst);
+    // 終了を待たずに捨てた run_blocking の結果と、取り込まれなかった send_external の message
+    BLOCKING_PENDING = 0;
+    BLOCKING_DONE.lock().unwrap().clear();
+    EXTERNAL.lock().unwrap().clear();
+    EXTERNAL_PENDING.store(false, Ordering::SeqCst);
+    // 完了していない slot は Killed で完了させる (await している側を待たせ続けない); 完了済みの slot は handle が削除する
+    for slot in COMPLETIONS.lock().unwrap().values_mut().filter(|slot| slot.result.is_none()) {
+        slot.result = Some(ExitReason::Killed);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+    #[cfg(feature = "testing")]
+    {
+        FORCED_NEXT = None;
+    }
+    free_global(ptr::addr_of_mut!(MESSAGES));
+    free_global(ptr::addr_of_mut!(WAITING));
+    free_global(ptr::addr_of_mut!(SUSPENDED));
+    free_global(ptr::addr_of_mut!(BLOCKING_RESULTS));
+    free_global(ptr::addr_of_mut!(EXITED));
+    free_global(ptr::addr_of_mut!(JOINERS));
+    free_global(ptr::addr_of_mut!(LINKS));
+    free_global(ptr::addr_of_mut!(TOKEN_WAITERS));
+    free_global(ptr::addr_of_mut!(CANCELLED));
+    free_global(ptr::addr_of_mut!(UNACKED));
+    free_global(ptr::addr_of_mut!(TRACKED));
+    free_global(ptr::addr_of_mut!(LOST_ACKS));
+    free_global(ptr::addr_of_mut!(POOLS));
+    free_global(ptr::addr_of_mut!(POOL_OF));
+    free_global(ptr::addr_of_mut!(GROUPS));
+    free_global(ptr::addr_of_mut!(LOCKED_STACKS));
+    free_global(ptr::addr_of_mut!(DEDUP_SEEN));
+    free_global(ptr::addr_of_mut!(WAITING_ON));
+    free_global(ptr::addr_of_mut!(WAIT_REASONS));
+    free_global(ptr::addr_of_mut!(BARRIERS));
+    free_global(ptr::addr_of_mut!(BROKEN));
+    free_global(ptr::addr_of_mut!(GUARD_FAULTS));
+    free_global(ptr::addr_of_mut!(MAILBOX_IDS));
+    #[cfg(debug_assertions)]
+    {
+        free_global(ptr::addr_of_mut!(SPAWN_COUNTS));
+        free_global(ptr::addr_of_mut!(WOKEN_BY));
+    }
+    free_global(ptr::addr_of_mut!(SENT_COUNT));
+    free_global(ptr::addr_of_mut!(RECV_COUNT));
+    free_global(ptr::addr_of_mut!(DROPPED_COUNT));
+    free_global(ptr::addr_of_mut!(PENDING_REPLIES));
+    free_global(ptr::addr_of_mut!(REPLIES));
+    *ID.lock().unwrap() = None;
+}
+
+// teardown が後始末をする前に数えた、session の終わりに残っていたもの (assert_no_leaks 用; 次の teardown まで残す)
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone, Copy)]
+struct SessionLeaks {
+    ids: usize,             // 返却されていない thread id
+    contexts: usize,        // 終了しないまま free_remaining で破棄された Context
+    pending_stacks: usize,  // rm_unused_stack の後も解放待ちのスタック
+    stack_bytes: usize,     // 確保されたまま解放されていないスタック領域 (STACK_TOTAL)
+}
+
+#[cfg(any(test, feature = "testing"))]
+static mut LAST_SESSION: SessionLeaks = SessionLeaks { ids: 0, contexts: 0, pending_stacks: 0, stack_bytes: 0 };
+
+#[cfg(any(test, feature = "testing"))]
+unsafe fn record_session(ids: usize, contexts: usize) {
+    LAST_SESSION = SessionLeaks { ids, contexts, pending_stacks: UNUSED_STACK.len(), stack_bytes: STACK_TOTAL };
+}
+
+// assert_no_leaks がない build では記録しない
+#[cfg(not(any(test, feature = "testing")))]
+unsafe fn record_session(_ids: usize, _contexts: usize) {}
+
+// test 用の global allocator が数える、ページ境界にアライメントされた確保 (スタック) のうち未解放のもの
+#[cfg(all(test, target_arch = "aarch64"))]
+static LIVE_STACK_ALLOCS: std::sync::atomic::AtomicIsize = std::sync::atomic::AtomicIsize::new(0);
+
+/*  spawn_from_main などが戻った後に呼び、直前の session の後始末に漏れがないことを確かめる (失敗すれば panic)
+    teardown が全てを片付ける前に記録した値で、id の返却、終了していない Context、解放待ちのスタック、スタックの確保と解放の対応を調べる
+    test build では、global allocator で数えたスタックの確保と解放の対応も調べる
+    stack-free の経路 (UNUSED_STACK, CONTEXT_POOL, kill, panic) を変更した際の regression 検出用 (test build と testing feature のみ)
+*/
+#[cfg(any(test, feature = "testing"))]
+pub fn assert_no_leaks() {
+    unsafe {
+        assert!(CTX_MAIN.is_none(), "assert_no_leaks: called while the runtime is running");
+        let leaks = LAST_SESSION;
+        assert!(leaks.ids == 0, "assert_no_leaks: {} thread id(s) were not released", leaks.ids);
+        assert!(leaks.contexts == 0, "assert_no_leaks: {} context(s) were still alive at the end of the session", leaks.contexts);
+        assert!(leaks.pending_stacks == 0, "assert_no_leaks: {} stack(s) still pending free", leaks.pending_stacks);
+        assert!(leaks.stack_bytes == 0, "assert_no_leaks: {} bytes of stack were allocated but never freed", leaks.stack_bytes);
+    }
+    #[cfg(all(test, target_arch = "aarch64"))]
+    {
+        let live = LIVE_STACK_ALLOCS.load(Ordering::SeqCst);
+        assert!(live == 0, "assert_no_leaks: {} stack allocation(s) were never deallocated", live);
+    }
+}
+
+// Box::into_raw で確保した global 変数を解放して null に戻す
+unsafe fn free_global<T>(p: *mut *mut T) {
+    if !(*p).is_null() {
+        drop(Box::from_raw(*p));
+        *p = ptr::null_mut();
+    }
+}
+
+// producer が consumer を追い越したまま終了した場合などのロジックエラー検出用; 警告した message の数を返す
+fn warn_undrained(msgs: &MappedList) -> usize {
+    let undrained = msgs.lens();
+    if undrained.is_empty() {
+        return 0;
+    }
+
+    let total: usize = undrained.iter().map(|(_, n)| n).sum();
+    eprintln!("warning: {} undrained message(s) dropped at shutdown", total);
+    for (key, n) in undrained {
+        eprintln!("    actor {}: {} message(s)", key, n);
+    }
+    total
+}
+
+/*  優先度スケジューリング: 実行可能な thread のうち最も優先度の高いものを CONTEXTS の先頭に移す
+    同じ優先度の中では queue の順序を保つ -> 優先度が全て等しければ従来の round robin と同じ
+*/
+unsafe fn select_next() {
+    #[cfg(feature = "testing")]
+    if let Some(id) = FORCED_NEXT.take() {
+        if move_to_front(id) {
+            return;
+        }
+    }
+
+    let mut best: Option<(usize, u8)> = None;
+    for (i, ctx) in CONTEXTS.iter().enumerate() {
+        if best.is_none_or(|(_, p)| ctx.priority > p) {
+            best = Some((i, ctx.priority));
+        }
+    }
+
+    if let Some((pos, _)) = best {
+        if pos > 0 {
+            let mut rest = CONTEXTS.split_off(pos);
+            let ctx = rest.pop_front().unwrap();
+            CONTEXTS.append(&mut rest);
+            CONTEXTS.push_front(ctx);
+        }
+    }
+}
+
+// テスト用: 次のコンテキストスイッチで (優先度に関係なく) 切り替える先を指定
+#[cfg(feature = "testing")]
+static mut FORCED_NEXT: Option<u64> = None;
+
+/*  次に schedule (park, 終了時を含む) が切り替える thread を id に固定する; 一度使われると解除される
+    id が実行可能 (CONTEXTS 内) でなければ何もせず false
+    報告された競合を、interleaving を順に指定して決定的に再現するためのもの
+*/
+actor_api! {
+    #[cfg(feature = "testing")]
+    pub fn set_next(id: u64) -> bool {
+        unsafe {
+            if CONTEXTS.iter().any(|ctx| ctx.thread_id == id) {
+                FORCED_NEXT = Some(id);
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+
+// 実行可能な thread id を queue の先頭へ移動; CONTEXTS になければ false
+unsafe fn move_to_front(id: u64) -> bool {
+    match CONTEXTS.iter().position(|ctx| ctx.thread_id == id) {
+        Some(0) => true,
+        Some(pos) => {
+            let mut rest = CONTEXTS.split_off(pos);
+            let ctx = rest.pop_front().unwrap();
+            CONTEXTS.append(&mut rest);
+            CONTEXTS.push_front(ctx);
+            true
+        },
+        None => false,
+    }
+}
+
+/*  実行可能な actor を queue の前方に移す (latency の調整用): 今いる位置と先頭の中間まで進める
+    先頭まで一気に移さないため、繰り返し boost しても他の actor を飢えさせない; 実行中の actor (先頭) は追い越さない
+    id が実行可能でない・すでに実行中の直後にいる場合は false
+*/
+actor_api! {
+    pub fn boost(id: u64) -> bool {
+        unsafe {
+            running("boost");
+            let pos = match CONTEXTS.iter().position(|ctx| ctx.thread_id == id) {
+                Some(pos) if pos > 1 => pos,
+                _ => return false,
+            };
+            let target = pos.div_ceil(2);     // 1 <= target < pos
+            let mut rest = CONTEXTS.split_off(pos);
+            let ctx = rest.pop_front().unwrap();
+            let mut tail = CONTEXTS.split_off(target);
+            CONTEXTS.push_back(ctx);
+            CONTEXTS.append(&mut tail);
+            CONTEXTS.append(&mut rest);
+            true
+        }
+    }
+}
+
+// 実行中の actor の優先度を変更; 次回のスケジューリングから反映される
+actor_api! {
+    pub fn set_priority(p: u8) {
+        assert!(p <= MAX_PRIORITY, "priority must be at most {}: {}", MAX_PRIORITY, p);
+        unsafe {
+            running("set_priority").priority = p;
+        }
+    }
+}
+
+actor_api! {
+    pub fn priority() -> u8 {
+        unsafe {
+            running("priority").priority
+        }
+    }
+}
+
+// 論理時計を進め、上限に達していれば全 actor を打ち切って main() に戻る
+unsafe fn tick() {
+    TICKS += 1;
+    if let Some(limit) = TICK_LIMIT {
+        if TICKS >= limit {
+            if let Some(c) = &CTX_MAIN {
+                switch_context(&**c as *const Registers);
+            }
+        }
+    }
+}
+
+// main() に戻ったあと、終了しなかった actor のスタックを解放し Context を破棄; 破棄した数を返す
+unsafe fn free_remaining() -> usize {
+    let mut remaining: Vec<ContextBox> = CONTEXTS.split_off(0).into_iter().collect();
+    remaining.extend((*WAITING).drain().map(|(_, ctx)| ctx));
+    remaining.extend((*SUSPENDED).drain().map(|(_, ctx)| ctx));
+    let n = remaining.len();
+    for ctx in remaining {
+        if ctx.owns_stack {
+            free_stack(ctx.stack, ctx.stack_layout, ctx.guard_size);     // main() のスタック上で動いているので解放できる
+        }
+        complete(ctx.thread_id, ExitReason::Killed);
+    }
+    n
+}
+
+actor_api! {
+    pub fn schedule() {
+        schedule_from("schedule");
+    }
+}
+
+// schedule() の本体; op は runtime の外で呼ばれた場合の panic message に使う (呼び出し元の API 名)
+fn schedule_from(op: &str) {
+    unsafe {
+        running(op);
+        tick();
+        wake_blocked();     // OS スレッドでの処理が完了した thread を実行可能にする
+
+        if CONTEXTS.len() == 1 {
+            return;
+        }
+        // weight の分の slice を使い切るまでは切り替えない
+        if let Some(ctx) = CONTEXTS.front_mut() {
+            if ctx.slices_left > 0 {
+                ctx.slices_left -= 1;
+                return;
+            }
+        }
+        run_on_schedule();      // 自分が CONTEXTS の先頭にいる間に呼ぶ (hook の panic で queue を壊さない)
+        RESCHEDULE.store(false, Ordering::SeqCst);      // これから切り替えるので、遅延された要求はここで満たされる
+
+        // queue からコンテキストを pop_front -> push_back
+        enter_critical("schedule");
+        let mut ctx = CONTEXTS.pop_front().unwrap();
+        ctx.slices_left = ctx.weight - 1;       // 次に実行権を得たときの分
+        let regs = ctx.get_regs_mut();      // get register data
+        CONTEXTS.push_back(ctx);
+
+        if set_context(regs) == 0 {     // 今の実行プロセスの状態を保存; 
+            select_next();
+            IN_SCHEDULER = false;       // 切り替え先は critical section の外から再開する
+            let next = CONTEXTS.front().unwrap();
+            switch_context((**next).get_regs());    // コンテキストスイッチ
+        }
+
+        rm_unused_stack();      // 不要なスタック領域を削除
+    }
+}
+
+// scheduler のデータ構造を変更する前に呼ぶ; 変更中に再入された場合はデータ構造を壊す前に panic
+unsafe fn enter_critical(op: &str) {
+    if IN_SCHEDULER {
+        panic!("{}: re-entered while the scheduler is mutating its queues", op);
+    }
+    IN_SCHEDULER = true;
+}
+
+/*  reschedule を要求する (async-signal-safe: atomic flag を立てるだけ)
+    preemption 用の signal handler からはこちらを呼び、schedule() を直接呼ばないこと
+    実際の切り替えは、次に send / receive / yield_now が安全な地点に達したときに行われる
+*/
+pub fn request_reschedule() {
+    RESCHEDULE.store(true, Ordering::SeqCst);
+}
+
+// 遅延された reschedule の要求があり、critical section の外かつ preempt_disable 中でなければ schedule() する
+unsafe fn safe_point() {
+    let preemptible = CONTEXTS.front().is_some_and(|ctx| ctx.preempt_off == 0);
+    if preemptible && !IN_SCHEDULER && RESCHEDULE.load(Ordering::SeqCst) {
+        schedule_from("safe_point");     // schedule() が flag を下ろす
+    }
+}
+
+/*  実行中の actor の preemption を一時的に禁止する (割り込み禁止の協調版); preempt_enable と対にして入れ子にできる
+    禁止中は request_reschedule による切り替えを行わず、最後の preempt_enable まで遅延する
+    yield_now や受信待ちなど、自分から実行権を譲る操作は禁止中でも通常どおり切り替わる
+*/
+actor_api! {
+    pub fn preempt_disable() {
+        unsafe {
+            running("preempt_disable").preempt_off += 1;
+        }
+    }
+}
+
+// preempt_disable を一段解除する; 0 に戻った時点で、禁止中に要求された reschedule があればここで行う
+actor_api! {
+    pub fn preempt_enable() {
+        unsafe {
+            let ctx = running("preempt_enable");
+            assert!(ctx.preempt_off > 0, "preempt_enable called without a matching preempt_disable");
+            ctx.preempt_off -= 1;
+            if ctx.preempt_off == 0 {
+                safe_point();
+            }
+        }
+    }
+}
+
+// 明示的に他の thread へ実行権を譲る
+actor_api! {
+    pub fn yield_now() {
+        schedule_from("yield_now");
+    }
+}
+
+/*  send したあと、受信側へ直接コンテキストスイッチする (request/reply を密に繰り返す場合の低レイテンシ化)
+    send + schedule() では起床した受信側が queue の末尾に積まれるため、間の thread を一巡してから受信側が動く
+    ここでは自分を queue の末尾に回し、受信側を先頭にしてから switch する -> 優先度 (select_next) は無視する
+    受信側が実行可能でない (受信待ちでない・send hook で破棄された・自分自身) 場合は通常の schedule() を行う
+*/
+actor_api! {
+    pub fn send_and_switch_to(key: u64, msg: u64) {
+        unsafe {
+            deliver(key, msg);
+            tick();
+            wake_blocked();
+
+            if !hand_off(key) {
+                schedule_from("send_and_switch_to");
+            }
+        }
+    }
+}
+
+/*  実行可能な thread へ直接実行権を譲る (受け渡しの相手が決まっている protocol 向け)
+    自分は queue の末尾に回り、id が次に実行される -> 優先度 (select_next) や間の thread は無視する
+    id が実行可能でない (受信待ち・停止中・終了済み・自分自身) 場合は何もせず false
+*/
+actor_api! {
+    pub fn yield_to(id: u64) -> bool {
+        unsafe {
+            running("yield_to");
+            tick();
+            wake_blocked();
+            hand_off(id)
+        }
+    }
+}
+
+// 実行中の thread を末尾に回し、key へ直接コンテキストスイッチする; key が実行可能でなければ false
+unsafe fn hand_off(key: u64) -> bool {
+    if key == current_id() || !CONTEXTS.iter().skip(1).any(|ctx| ctx.thread_id == key) {
+        return false;
+    }
+
+    RESCHEDULE.store(false, Ordering::SeqCst);
+    enter_critical("hand_off");
+    let mut ctx = CONTEXTS.pop_front().unwrap();
+    let regs = ctx.get_regs_mut();
+    CONTEXTS.push_back(ctx);
+    move_to_front(key);
+
+    if set_context(regs) == 0 {
+        IN_SCHEDULER = false;
+        let next = CONTEXTS.front().unwrap();
+        switch_context((**next).get_regs());    // key へ直接コンテキストスイッチ
+    }
+
+    rm_unused_stack();
+    true
+}
+
+unsafe fn rm_unused_stack() {
+    // 先に UNUSED_STACK を空にしてから手元の複製を解放する -> 解放の途中で再入 (free_stack 内の panic など) しても同じスタックを二重に解放しない
+    let unused = std::mem::take(&mut *ptr::addr_of_mut!(UNUSED_STACK));
+    for (stack, layout, guard_size) in unused {
+        free_stack(stack, layout, guard_size);
+    }
+}
+
+// ガードページの保護を解除してからスタック領域を解放
+unsafe fn free_stack(stack: *mut u8, layout: Layout, guard_size: usize) {
+    if !LOCKED_STACKS.is_null() && (*LOCKED_STACKS).remove(&(stack as usize)) {
+        // 解放しても unmap されるとは限らないため、固定を解除してから返す
+        if let Err(errno) = PLATFORM.unlock(stack.add(guard_size), layout.size() - guard_size) {
+            eprintln!("warning: failed to munlock the stack at {:p}: {}", stack, errno);
+        }
+    }
+    STACK_TOTAL -= layout.size();
+    // ガードページを戻せなかった領域を allocator に返すと、次に使う側がアクセス不可のページを踏むため leak させる
+    if let Err(errno) = PLATFORM.unprotect(stack, guard_size) {
+        eprintln!("warning: failed to unprotect the stack guard page at {:p}: {}; leaking the stack", stack, errno);
+        LEAKED_STACK_BYTES += layout.size();
+        return;
+    }
+    PLATFORM.dealloc_stack(stack, layout);
+}
+
+// send hook: (宛先, message) を受け取り、Some(新しい message) で書き換え、None で破棄
+// logging や metrics, 認可などを全 actor に手を入れずに差し込むためのもの; 一度に一つだけ登録できる
+type SendHook = Box<dyn Fn(u64, u64) -> Option<u64>>;
+static mut SEND_HOOK: Option<SendHook> = None;
+
+pub fn set_send_hook(f: SendHook) {
+    unsafe {
+        SEND_HOOK = Some(f);
+    }
+}
+
+pub fn clear_send_hook() {
+    unsafe {
+        SEND_HOOK = None;
+    }
+}
+
+// send hook があれば配送前に message を変換 (None なら破棄)
+unsafe fn apply_send_hook(key: u64, msg: u64) -> Option<u64> {
+    match &SEND_HOOK {
+        Some(hook) => hook(key, msg),
+        None => Some(msg),
+    }
+}
+
+/*  schedule hook: schedule() がコンテキストスイッチする直前に、切り替え前の actor 上で呼ばれる
+    queue の深さの sampling, deadline の確認などに使う; 一度に一つだけ登録できる
+    呼び出し中は hook を取り外しておくため、hook の中から yield しても再帰しない (hook の中で登録し直すことも可能)
+    hook が panic すると hook は外れたままになり、panic は呼び出し元の actor で起きたものとして扱われる
+*/
+static mut ON_SCHEDULE: Option<Box<dyn Fn()>> = None;
+
+pub fn set_on_schedule(f: Box<dyn Fn()>) {
+    unsafe {
+        ON_SCHEDULE = Some(f);
+    }
+}
+
+pub fn clear_on_schedule() {
+    unsafe {
+        ON_SCHEDULE = None;
+    }
+}
+
+unsafe fn run_on_schedule() {
+    if let Some(hook) = ON_SCHEDULE.take() {
+        hook();
+        if ON_SCHEDULE.is_none() {
+            ON_SCHEDULE = Some(hook);
+        }
+    }
+}
+
+/*  actor 間の message のやり取り
+    順序の保証: enqueue (deliver) は送信側の actor 上で、起床・yield より前に完了する
+    -> 単一の OS スレッド上で協調的に動くため、enqueue と起床の間に他の send が割り込むことはない
+    -> 宛先の mailbox (FIFO) には send が呼ばれた順に並び、起床した宛先がいつ実際に再開しても、先に send された message から受信する
+    例外: PriorityMailbox は優先度順、RingMailbox は溢れた古い message を捨てる
+          send_external は schedule / park で取り込まれた時点で、その時の末尾に積まれる
+    返り値: 受信待ちだった宛先を起こした数 (0 or 1); 0 なら宛先は実行中か、message は backlog に積まれただけ
+*/
+#[cfg(feature = "global_runtime")]
+pub fn send(key: u64, msg: u64) -> usize {
+    send_impl(key, msg)
+}
+
+fn send_impl(key: u64, msg: u64) -> usize {
+    let (_, woken) = unsafe {deliver(key, msg)};
+    after_deliver("send");
+    woken as usize
+}
+
+/*  送信系の関数で配送した後の共通処理
+    send_yields なら schedule() (協調的マルチタスク: actor 側が scheduling 実行)
+    そうでなければ起床のみで yield は呼び出し側が yield_now() で行う; 要求された reschedule だけは行う
+*/
+fn after_deliver(op: &str) {
+    if unsafe {CONFIG.send_yields} {
+        schedule_from(op);
+    } else {
+        unsafe {safe_point()};
+    }
+}
+
+// actor を group に参加させる (参加済みなら何もしない)
+actor_api! {
+    pub fn join_group(name: &str, id: u64) {
+        unsafe {
+            running("join_group");
+            let members = (*GROUPS).entry(name.to_string()).or_default();
+            if !members.contains(&id) {
+                members.push(id);
+            }
+        }
+    }
+}
+
+// actor を group から外す; 最後の member が抜けた group は削除
+actor_api! {
+    pub fn leave_group(name: &str, id: u64) {
+        unsafe {
+            running("leave_group");
+            if let Some(members) = (*GROUPS).get_mut(name) {
+                members.retain(|member| *member != id);
+                if members.is_empty() {
+                    (*GROUPS).remove(name);
+                }
+            }
+        }
+    }
+}
+
+// group の生存中の member (参加順)
+pub fn group_members(name: &str) -> Vec<u64> {
+    unsafe {
+        running("group_members");
+        (*GROUPS).get(name).map_or(Vec::new(), |members| members.iter().copied().filter(|id| is_alive(*id)).collect())
+    }
+}
+
+/*  group の中で mailbox の最も短い member に send する (負荷分散); 送った member の thread_id を返す
+    同じ長さなら thread_id の小さい方; 終了済みの member はここで group から取り除く
+    生存中の member がいなければ送らずに None
+*/
+actor_api! {
+    pub fn send_to_least_loaded(name: &str, msg: u64) -> Option<u64> {
+        let target = unsafe {
+            running("send_to_least_loaded");
+            let members = (*GROUPS).get_mut(name)?;
+            members.retain(|id| is_alive(*id));
+            let target = members.iter().copied().min_by_key(|id| ((*MESSAGES).len(*id), *id));
+            if members.is_empty() {
+                (*GROUPS).remove(name);
+            }
+            target?
+        };
+        send_impl(target, msg);
+        Some(target)
+    }
+}
+
+/*  冪等な処理のための重複排除付き send: 同じ宛先に直近 dedup_window 個以内に送られた dedup_id と同じなら破棄する
+    破棄した場合は配送も yield もせず false; 受け付けた場合は send と同じ
+    dedup_id は配送前に記録する (send hook や mailbox の上限で破棄されても、同じ dedup_id の再送は重複として扱う)
+*/
+actor_api! {
+    pub fn send_dedup(key: u64, msg: u64, dedup_id: u64) -> bool {
+        unsafe {
+            running("send_dedup");
+            let window = CONFIG.dedup_window;
+            let seen = (*DEDUP_SEEN).entry(key).or_default();
+            if seen.contains(&dedup_id) {
+                return false;
+            }
+            seen.push_back(dedup_id);
+            while seen.len() > window {
+                seen.pop_front();
+            }
+        }
+        send_impl(key, msg);
+        true
+    }
+}
+
+/*  send と同じだが、message が宛先の mailbox に積まれた位置 (積んだ直後の要素数 - 1) を返す
+    因果順序の検証などの診断用; FIFO 以外の mailbox では取り出し順の位置とは限らない
+    send hook や mailbox の上限で破棄された場合は None
+*/
+actor_api! {
+    pub fn send_indexed(key: u64, msg: u64) -> Option<usize> {
+        let index = unsafe {
+            let (delivered, _) = deliver(key, msg);
+            delivered.map(|_| (*MESSAGES).len(key) - 1)
+        };
+        after_deliver("send_indexed");
+        index
+    }
+}
+
+// 同じ message を複数の宛先に送る; schedule() は最後に一度だけ行う
+// 返り値: 受信待ちだった宛先を起こした数
+actor_api! {
+    pub fn broadcast(keys: &[u64], msg: u64) -> usize {
+        let woken = keys.iter().filter(|key| unsafe {deliver(**key, msg)}.1).count();
+        after_deliver("broadcast");
+        woken
+    }
+}
+
+// message を mailbox に積んで宛先を起こす (yield はしない); (実際に配送された message, 受信待ちの宛先を起こしたか) を返す
+unsafe fn deliver(key: u64, msg: u64) -> (Option<u64>, bool) {
+    deliver_prio(key, msg, 0)
+}
+
+// prio は宛先が priority mailbox の場合のみ使われる
+unsafe fn deliver_prio(key: u64, msg: u64, prio: u8) -> (Option<u64>, bool) {
+    deliver_tracked(key, Envelope::new(msg), prio)
+}
+
+/*  env.ack_id があれば send_tracked の message として積む (UNACKED / TRACKED に登録)
+    send hook・上限で捨てられた場合、宛先が終了済みの場合、mailbox の容量で捨てられた場合は ack を失敗させる
+    max_mailbox_bytes は env.bytes で判定する
+*/
+unsafe fn deliver_tracked(key: u64, env: Envelope, prio: u8) -> (Option<u64>, bool) {
+    let sender = running("send").thread_id;     // runtime の外では global 変数が未初期化なので先に確認
+    *(*SENT_COUNT).entry(sender).or_insert(0) += 1;
+    let Envelope { msg, ack_id, bytes } = env;
+
+    // message 送信
+    let msg = match apply_send_hook(key, msg) {
+        Some(_) if mailbox_full(key, bytes) => {       // 上限を超える message は黙って捨てて数える (失敗を扱うなら try_send)
+            *(*DROPPED_COUNT).entry(key).or_insert(0) += 1;
+            None
+        },
+        msg => msg,
+    };
+
+    let mut woken = false;
+    let msg = match msg {
+        Some(msg) => {
+            enter_critical("send");
+            let pushed = (*MESSAGES).push(key, Envelope { msg, ack_id, bytes }, prio);     // 優先度を扱わない mailbox では末尾に積むだけ
+            if let PushResult::Rejected(_) = pushed {       // 満杯の Bounded mailbox: max_mailbox_bytes と同じく捨てて数える
+                IN_SCHEDULER = false;
+                *(*DROPPED_COUNT).entry(key).or_insert(0) += 1;
+                None
+            } else {
+                if let Some(ack_id) = ack_id {
+                    (*UNACKED).insert(ack_id, (sender, msg));
+                    (*TRACKED).entry(key).or_default().push(ack_id);
+                }
+                woken = wake(key);
+                IN_SCHEDULER = false;
+                if let PushResult::Evicted(Envelope { ack_id: Some(evicted), .. }) = pushed {      // 溢れて捨てられた追跡中の message
+                    fail_ack(evicted);
+                }
+                if let Some(ack_id) = ack_id.filter(|_| !is_alive(key) && !is_mailbox(key)) {      // 終了済みの宛先は受信しない
+                    fail_ack(ack_id);
+                }
+                Some(msg)
+            }
+        },
+        None => None,
+    };
+    if let (None, Some(ack_id)) = (msg, ack_id) {
+        (*LOST_ACKS).insert(ack_id);
+    }
+    (msg, woken)
+}
+
+// 1 message あたりの既定の大きさ: payload の u64 (send_sized で申告しない場合)
+const MESSAGE_BYTES: usize = std::mem::size_of::<u64>();
+
+// actor の mailbox に積まれている message の大きさ (Envelope::bytes) の合計バイト数; runtime の外では 0
+pub fn mailbox_bytes(id: u64) -> usize {
+    unsafe {
+        if MESSAGES.is_null() {
+            return 0;
+        }
+        (*MESSAGES).bytes(id)
+    }
+}
+
+// max_mailbox_bytes を超えた、または Bounded の mailbox が満杯だったため、actor の mailbox に積まれずに捨てられた message の数; runtime の外では 0
+pub fn dropped_messages(id: u64) -> u64 {
+    unsafe {
+        if DROPPED_COUNT.is_null() {
+            return 0;
+        }
+        (*DROPPED_COUNT).get(&id).copied().unwrap_or(0)
+    }
+}
+
+// 大きさ bytes の message を積むと max_mailbox_bytes を超えるか
+unsafe fn mailbox_full(key: u64, bytes: usize) -> bool {
+    mailbox_bytes(key).saturating_add(bytes) > CONFIG.max_mailbox_bytes
+}
+
+// send と同じだが、宛先の mailbox が max_mailbox_bytes に達していれば送らずに Err(MailboxFull)
+actor_api! {
+    pub fn try_send(key: u64, msg: u64) -> Result<usize, ActorError> {
+        try_send_sized(key, msg, MESSAGE_BYTES)
+    }
+}
+
+/*  send と同じだが、max_mailbox_bytes の計算で message の大きさを bytes とする
+    payload が大きな領域への handle (Box::into_raw した buffer の address など) の場合に、その領域の大きさを申告する
+    上限を超える場合は send と同じく捨てて dropped_messages に数える
+*/
+actor_api! {
+    pub fn send_sized(key: u64, msg: u64, bytes: usize) -> usize {
+        let (_, woken) = unsafe {deliver_tracked(key, Envelope { bytes, ..Envelope::new(msg) }, 0)};
+        after_deliver("send_sized");
+        woken as usize
+    }
+}
+
+// send_sized と同じだが、積むと max_mailbox_bytes を超える場合は送らずに Err(MailboxFull)
+actor_api! {
+    pub fn try_send_sized(key: u64, msg: u64, bytes: usize) -> Result<usize, ActorError> {
+        unsafe {
+            running("try_send");
+            if mailbox_full(key, bytes) {
+                return Err(ActorError::MailboxFull { id: key, bytes: mailbox_bytes(key), limit: CONFIG.max_mailbox_bytes });
+            }
+        }
+        Ok(send_sized(key, msg, bytes))
+    }
+}
+
+/*  優先度付き送信: 宛先が priority mailbox (ActorBuilder::priority_mailbox) なら、
+    prio の大きい message が先に receive される (同じ優先度は FIFO, 通常の send は優先度 0)
+    priority mailbox でない宛先には send と同じく末尾に積む
+*/
+actor_api! {
+    pub fn send_prio(key: u64, msg: u64, prio: u8) {
+        unsafe {deliver_prio(key, msg, prio)};
+        after_deliver("send_prio");
+    }
+}
+
+// mailbox から message を取り出したときの共通処理: 受信数の記録と send_tracked の自動 ack; payload を返す
+unsafe fn on_dequeue(key: u64, env: Envelope) -> u64 {
+    *(*RECV_COUNT).entry(key).or_insert(0) += 1;
+    if let Some(ack_id) = env.ack_id {
+        ack(ack_id);
+    }
+    env.msg
+}
+
+/*  到達確認付き送信: 宛先がこの message を取り出した (receive した) 時点で ack され、送信側に通知される
+    取り出されないことが確定した場合 (宛先の終了、send hook・容量による破棄、drain_all) は ack が失敗する
+*/
+actor_api! {
+    pub fn send_tracked(key: u64, msg: u64) -> u64 {
+        let ack_id = unsafe {
+            NEXT_ACK += 1;
+            deliver_tracked(key, Envelope { ack_id: Some(NEXT_ACK), ..Envelope::new(msg) }, 0);
+            NEXT_ACK
+        };
+        after_deliver("send_tracked");
+        ack_id
+    }
+}
+
+// 明示的に ack し、送信側を起こす
+actor_api! {
+    pub fn ack(ack_id: u64) {
+        unsafe {
+            if let Some((sender, _)) = (*UNACKED).remove(&ack_id) {
+                untrack(ack_id);
+                wake(sender);
+            }
+        }
+    }
+}
+
+// 受信されないことが確定した message の ack を失敗させ、wait_ack している送信側を起こす
+unsafe fn fail_ack(ack_id: u64) {
+    if let Some((sender, _)) = (*UNACKED).remove(&ack_id) {
+        untrack(ack_id);
+        (*LOST_ACKS).insert(ack_id);
+        wake(sender);
+    }
+}
+
+unsafe fn untrack(ack_id: u64) {
+    for ids in (*TRACKED).values_mut() {
+        ids.retain(|id| *id != ack_id);
+    }
+    (*TRACKED).retain(|_, ids| !ids.is_empty());
+}
+
+pub fn is_acked(ack_id: u64) -> bool {
+    unsafe {
+        !(*UNACKED).contains_key(&ack_id) && !(*LOST_ACKS).contains(&ack_id)
+    }
+}
+
+// ack されるまで受信待ち状態で待つ; 受信されないことが確定したら (宛先の終了など) false
+actor_api! {
+    pub fn wait_ack(ack_id: u64) -> bool {
+        unsafe {
+            loop {
+                if (*LOST_ACKS).contains(&ack_id) {
+                    return false;
+                }
+                if is_acked(ack_id) {
+                    return true;
+                }
+                park_for(WaitReason::Ack(ack_id));
+            }
+        }
+    }
+}
+
+// 最大 rounds 回 yield するまで ack を待つ; ack されたら true (受信されないことが確定した場合はすぐに false)
+actor_api! {
+    pub fn wait_ack_timeout(ack_id: u64, rounds: u64) -> bool {
+        for _ in 0..rounds {
+            if is_acked(ack_id) {
+                return true;
+            }
+            if unsafe {(*LOST_ACKS).contains(&ack_id)} {
+                return false;
+            }
+            yield_now();
+        }
+        is_acked(ack_id)
+    }
+}
+
+// runtime の session 中 (spawn_from_main などの中で、global 変数が初期化済み) か
+// true でも main() のコンテキストで実行中の場合があるため、actor 内かどうかは CONTEXTS も確認する
+pub fn in_runtime() -> bool {
+    unsafe {
+        CTX_MAIN.is_some() && !CONTEXTS.is_empty()
+    }
+}
+
+// 実行中の actor の thread_id
+actor_api! {
+    pub fn current_id() -> u64 {
+        unsafe {
+            running("current_id").thread_id
+        }
+    }
+}
+
+// 実行中の actor の Context; actor の外 (CONTEXTS が空) なら deadlock と区別できる panic にする
+unsafe fn running(op: &str) -> &'static mut Context {
+    match CONTEXTS.front_mut() {
+        Some(ctx) => ctx,
+        None => panic!("{}: {}", op, ActorError::NotInActorContext),
+    }
+}
+
+// 自分の mailbox の末尾に message を積む (後続処理の予約): yield も起床もせず、次回以降の receive で取り出される
+// 自分宛てなので send hook と送信数の記録は通さない
+actor_api! {
+    pub fn post_self(msg: u64) {
+        unsafe {
+            (*MESSAGES).push_back(current_id(), msg);
+        }
+    }
+}
+
+// 自分の mailbox に message が届いているか (消費も yield もしない)
+actor_api! {
+    pub fn poll_messages() -> bool {
+        mailbox_len(current_id()) > 0
+    }
+}
+
+// 自分の mailbox の次の message (消費も yield もしない); 空なら None
+actor_api! {
+    pub fn peek() -> Option<u64> {
+        unsafe {
+            let key = running("peek").thread_id;
+            (*MESSAGES).peek(key)
+        }
+    }
+}
+
+// actor の mailbox の message 数; mailbox が空 (削除済み)・actor が存在しない・runtime の外ではいずれも 0
+pub fn mailbox_len(id: u64) -> usize {
+    unsafe {
+        if MESSAGES.is_null() {
+            return 0;
+        }
+        (*MESSAGES).len(id)
+    }
+}
+
+/*  actor を持たない mailbox への参照カウント付き handle (channel 的な用途)
+    clone した handle はすべて同じ mailbox を指し、最後の handle が drop された時点で
+    読まれずに残っている message ごと mailbox を削除し、id を返却する -> 作って放置した mailbox が leak しない
+    id は actor の thread_id と重ならないように取るが、actor ではないため is_alive / live_actors には現れず join もできない
+*/
+#[derive(Clone)]
+pub struct MailboxHandle(Rc<MailboxId>);
+
+struct MailboxId(u64);
+
+impl MailboxHandle {
+    pub fn new() -> Self {
+        unsafe {
+            running("MailboxHandle::new");
+            loop {
+                let rnd = rand::random::<u64>();
+                if !is_alive(rnd) && (*MAILBOX_IDS).insert(rnd) {      // actor とも他の mailbox とも重ならない id
+                    return MailboxHandle(Rc::new(MailboxId(rnd)));
+                }
+            }
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0 .0
+    }
+
+    // mailbox の末尾に積む (yield はしない); send hook や mailbox の上限は send と同じく適用される
+    pub fn push(&self, msg: u64) {
+        unsafe {
+            deliver(self.id(), msg);
+        }
+    }
+
+    // 先頭の message を取り出す; 空なら None (受信待ちはしない)
+    pub fn pop(&self) -> Option<u64> {
+        unsafe {
+            running("MailboxHandle::pop");
+            (*MESSAGES).pop_front(self.id()).map(|env| on_dequeue(self.id(), env))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        mailbox_len(self.id())
+    }
+}
+
+impl Default for MailboxHandle {
+    fn default() -> Self {
+        MailboxHandle::new()
+    }
+}
+
+impl Drop for MailboxId {
+    fn drop(&mut self) {
+        unsafe {
+            if MESSAGES.is_null() {     // runtime 終了後: mailbox は teardown で破棄済み
+                return;
+            }
+            (*MESSAGES).remove(self.0);
+            if let Some(ack_ids) = (*TRACKED).remove(&self.0) {     // 読まれずに捨てた追跡中の message
+                for ack_id in ack_ids {
+                    fail_ack(ack_id);
+                }
+            }
+            (*MAILBOX_IDS).remove(&self.0);
+        }
+    }
+}
+
+/*  mailbox の checkpoint: actor の未処理 message を path に書き出す (mailbox は変更しない)
+    crash からの復旧 (at-least-once の処理) 用; restore_mailbox で読み戻す
+    形式は message ごとに 8 バイトの little endian を受信順に並べたもの
+    payload が u64 の既定の FIFO mailbox のみ対応 (priority / ring / custom では io::ErrorKind::Unsupported)
+*/
+pub fn checkpoint_mailbox(id: u64, path: &Path) -> io::Result<()> {
+    unsafe {
+        if MESSAGES.is_null() {
+            return Err(io::Error::other("checkpoint_mailbox: runtime is not running"));
+        }
+        if !(*MESSAGES).is_fifo(id) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "checkpoint_mailbox: only FIFO mailboxes can be checkpointed"));
+        }
+        enter_critical("checkpoint_mailbox");
+        let msgs = (*MESSAGES).messages(id);
+        IN_SCHEDULER = false;
+        let bytes: Vec<u8> = msgs.iter().flat_map(|env| env.msg.to_le_bytes()).collect();
+        fs::write(path, bytes)
+    }
+}
+
+/*  checkpoint_mailbox で書き出した message を actor の mailbox の末尾に積む; 積んだ message 数を返す
+    受信待ちの actor は起こす; 送信としては数えない (send hook, mailbox の上限も通さない)
+*/
+pub fn restore_mailbox(id: u64, path: &Path) -> io::Result<usize> {
+    let bytes = fs::read(path)?;
+    if bytes.len() % MESSAGE_BYTES != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "restore_mailbox: truncated checkpoint"));
+    }
+    unsafe {
+        if MESSAGES.is_null() {
+            return Err(io::Error::other("restore_mailbox: runtime is not running"));
+        }
+        if !(*MESSAGES).is_fifo(id) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "restore_mailbox: only FIFO mailboxes can be restored"));
+        }
+        enter_critical("restore_mailbox");
+        let mut count = 0;
+        for chunk in bytes.chunks_exact(MESSAGE_BYTES) {
+            (*MESSAGES).push_back(id, u64::from_le_bytes(chunk.try_into().unwrap()));
+            count += 1;
+        }
+        if count > 0 {
+            wake(id);
+        }
+        IN_SCHEDULER = false;
+        Ok(count)
+    }
+}
+
+// set_my_mailbox_policy で選べる mailbox の扱い (いずれも到着順)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MailboxPolicy {
+    Unbounded,          // 上限なし (既定の FIFO)
+    Bounded(usize),     // 容量に達したら新しい message を捨てる
+    DropOldest(usize),  // 容量に達したら最も古い message を捨てる
+}
+
+/*  実行中の actor が自分の mailbox の扱いを切り替える (message の洪水を検出した時にメモリを守るなど)
+    以降の send から新しい扱いが適用される; 積まれている message は容量に収まるよう切り詰める
+    Bounded は古い方から容量分を残し、DropOldest は新しい方から容量分を残す
+    容量 0 は panic; priority / custom の mailbox も到着順の mailbox に置き換わる
+*/
+actor_api! {
+    pub fn set_my_mailbox_policy(policy: MailboxPolicy) {
+        unsafe {
+            let key = running("set_my_mailbox_policy").thread_id;
+            let kind = match policy {
+                MailboxPolicy::Unbounded => MailboxKind::Fifo,
+                MailboxPolicy::Bounded(capacity) => MailboxKind::Bounded(capacity),
+                MailboxPolicy::DropOldest(capacity) => MailboxKind::Ring(capacity),
+            };
+            if let MailboxPolicy::Bounded(0) | MailboxPolicy::DropOldest(0) = policy {
+                panic!("set_my_mailbox_policy: capacity must be at least 1");     // 空の mailbox では次の send まで検出されないため先に確認
+            }
+            enter_critical("set_my_mailbox_policy");
+            let dropped = (*MESSAGES).reconfigure(key, kind);
+            IN_SCHEDULER = false;
+            for ack_id in dropped.into_iter().filter_map(|env| env.ack_id) {
+                fail_ack(ack_id);
+            }
+        }
+    }
+}
+
+/*  全ての actor の未処理 message を取り出し、宛先ごとにまとめて返す (shutdown 時の保存・記録用)
+    順序は各 mailbox の取り出し順 (受信していれば受け取ったはずの順)
+    受信中の actor と競合しないよう、shutdown() 後など actor が message を処理しなくなってから呼ぶこと
+    取り出した message は受信されたものとして数えない (message_stats は変わらない)
+*/
+pub fn drain_all() -> HashMap<u64, Vec<u64>> {
+    unsafe {
+        if MESSAGES.is_null() {
+            return HashMap::new();
+        }
+        enter_critical("drain_all");
+        let drained = (*MESSAGES).drain_all();
+        IN_SCHEDULER = false;
+        drained.into_iter().map(|(key, envs)| {
+            let msgs = envs.iter().map(|env| env.msg).collect();
+            for ack_id in envs.into_iter().filter_map(|env| env.ack_id) {
+                fail_ack(ack_id);       // 取り出した message は宛先に受信されない
+            }
+            (key, msgs)
+        }).collect()
+    }
+}
+
+/*  actor がガードページに触れて StackOverflow で終了したかどうか: 0 か 1 の flag (終了後も同じ runtime 内であれば参照できる)
+    回収した fault で actor は終了し、スタックも伸長しないため、2 以上になることはない (回数としては数えられない)
+    返り値が u64 なのは複数の actor の値をそのまま足し合わせられるようにするため (溢れた actor の数になる)
+    回収に対応していない環境ではガードページに触れるとプロセスが落ちるため常に 0
+*/
+pub fn guard_faults(id: u64) -> u64 {
+    unsafe {
+        if GUARD_FAULTS.is_null() {
+            return 0;
+        }
+        (*GUARD_FAULTS).get(&id).copied().unwrap_or(0)
+    }
+}
+
+// actor の (送信数, 受信数); runtime の外では (0, 0)
+pub fn message_stats(id: u64) -> (u64, u64) {
+    unsafe {
+        if SENT_COUNT.is_null() || RECV_COUNT.is_null() {
+            return (0, 0);
+        }
+        let sent = (*SENT_COUNT).get(&id).copied().unwrap_or(0);
+        let recv = (*RECV_COUNT).get(&id).copied().unwrap_or(0);
+        (sent, recv)
+    }
+}
+
+/*  explicit_runtime feature: runtime の状態を free function ではなく Runtime handle 経由で操作する
+    Runtime は actor の中でしか得られず、OS スレッドをまたいで持ち出せない (!Send)
+    -> runtime 外や別スレッドからの呼び出しを型で防ぐ; 実装は global_runtime と共通
+*/
+#[cfg(feature = "explicit_runtime")]
+pub struct Runtime {
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+// impl Runtime の中で、同名の free function に委譲するメソッドを並べて定義する
+#[cfg(feature = "explicit_runtime")]
+macro_rules! runtime_methods {
+    ($($(#[$attr:meta])* fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?;)*) => {
+        $(
+            $(#[$attr])*
+            pub fn $name(&self, $($arg: $ty),*) $(-> $ret)? {
+                $name($($arg),*)
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "explicit_runtime")]
+impl Runtime {
+    // 実行中の actor からのみ Some
+    pub fn current() -> Option<Runtime> {
+        unsafe {
+            if CTX_MAIN.is_some() && !CONTEXTS.is_empty() {
+                Some(Runtime { _not_send: std::marker::PhantomData })
+            } else {
+                None
+            }
+        }
+    }
+
+    pub fn spawn(&self, func: Entry, stack_size: usize) -> u64 {
+        spawn_impl(func, stack_size)
+    }
+
+    pub fn send(&self, key: u64, msg: u64) -> usize {
+        send_impl(key, msg)
+    }
+
+    pub fn receive(&self) -> Option<u64> {
+        receive_impl()
+    }
+
+    pub unsafe fn spawn_with_stack(&self, func: Entry, stack: *mut u8, size: usize) -> u64 {
+        spawn_with_stack(func, stack, size)
+    }
+
+    pub fn spawn_try<F, E>(&self, f: F, stack_size: usize) -> u64
+    where
+        F: FnOnce() -> Result<(), E> + 'static,
+        E: fmt::Debug,
+    {
+        spawn_try(f, stack_size)
+    }
+
+    pub fn spawn_fn<F>(&self, f: F, stack_size: usize) -> u64
+    where
+        F: FnOnce() -> u64 + 'static,
+    {
+        spawn_fn(f, stack_size)
+    }
+
+    pub fn spawn_with_result<F>(&self, f: F, stack_size: usize) -> (u64, ResultHandle)
+    where
+        F: FnOnce() -> u64 + 'static,
+    {
+        spawn_with_result(f, stack_size)
+    }
+
+    pub fn spawn_async<F>(&self, f: F, stack_size: usize) -> (u64, AsyncJoinHandle)
+    where
+        F: FnOnce() -> u64 + 'static,
+    {
+        spawn_async(f, stack_size)
+    }
+
+    pub fn parallel_for<F: Fn(usize) + Sync + 'static>(&self, n: usize, chunk: usize, f: F) {
+        parallel_for(n, chunk, f)
+    }
+
+    pub fn run_loop<F: FnMut(u64) -> ControlFlow<()>>(&self, f: F) {
+        run_loop(f)
+    }
+
+    pub fn run_blocking<F>(&self, f: F) -> u64
+    where
+        F: FnOnce() -> u64 + Send + 'static,
+    {
+        run_blocking(f)
+    }
+
+    // 以下は同名の free function (actor_api!) にそのまま委譲する
+    runtime_methods! {
+        fn try_spawn(func: Entry, stack_size: usize) -> Result<u64, ActorError>;
+        fn spawn_many(func: Entry, stack_size: usize, n: usize) -> Vec<u64>;
+        fn spawn_mlocked(func: Entry, stack_size: usize) -> Result<u64, ActorError>;
+        fn spawn_suspended(func: Entry, stack_size: usize) -> u64;
+        fn resume(id: u64) -> bool;
+        fn spawn_guarded(func: Entry, stack_size: usize) -> ActorGuard;
+        fn spawn_with_msg(func: Entry, stack_size: usize, init: u64) -> u64;
+        fn kill(id: u64) -> bool;
+        fn link(a: u64, b: u64);
+        fn unlink(a: u64, b: u64);
+        fn trap_exit(enabled: bool);
+        fn join(id: u64) -> ExitReason;
+        fn try_join(id: u64) -> Result<ExitReason, ActorError>;
+        fn join_timeout(id: u64, rounds: u64) -> Option<ExitReason>;
+        fn queue_position() -> usize;
+        fn runnable_others() -> usize;
+        fn kill_tree(root: u64);
+        #[cfg(feature = "testing")]
+        fn set_next(id: u64) -> bool;
+        fn boost(id: u64) -> bool;
+        fn set_priority(p: u8);
+        fn priority() -> u8;
+        fn schedule();
+        fn preempt_disable();
+        fn preempt_enable();
+        fn yield_now();
+        fn send_and_switch_to(key: u64, msg: u64);
+        fn yield_to(id: u64) -> bool;
+        fn join_group(name: &str, id: u64);
+        fn leave_group(name: &str, id: u64);
+        fn send_to_least_loaded(name: &str, msg: u64) -> Option<u64>;
+        fn send_dedup(key: u64, msg: u64, dedup_id: u64) -> bool;
+        fn send_indexed(key: u64, msg: u64) -> Option<usize>;
+        fn broadcast(keys: &[u64], msg: u64) -> usize;
+        fn try_send(key: u64, msg: u64) -> Result<usize, ActorError>;
+        fn send_sized(key: u64, msg: u64, bytes: usize) -> usize;
+        fn try_send_sized(key: u64, msg: u64, bytes: usize) -> Result<usize, ActorError>;
+        fn send_prio(key: u64, msg: u64, prio: u8);
+        fn send_tracked(key: u64, msg: u64) -> u64;
+        fn ack(ack_id: u64);
+        fn wait_ack(ack_id: u64) -> bool;
+        fn wait_ack_timeout(ack_id: u64, rounds: u64) -> bool;
+        fn current_id() -> u64;
+        fn post_self(msg: u64);
+        fn poll_messages() -> bool;
+        fn peek() -> Option<u64>;
+        fn set_my_mailbox_policy(policy: MailboxPolicy);
+        fn receive_with_depth() -> Option<(u64, usize)>;
+        fn spawn_pool(func: Entry, stack_size: usize, n: usize) -> u64;
+        fn pool_submit(pool: u64, job: u64);
+        fn pool_receive() -> Option<u64>;
+        fn barrier(barrier_id: u64, participants: usize) -> Result<(), ActorError>;
+        fn receive_cancellable(token: CancelToken) -> Option<u64>;
+        fn cancel(token: CancelToken);
+        fn shutdown();
+        fn send_then_receive(target: u64, request: u64) -> u64;
+        fn call_with_correlation(target: u64, request: u64) -> (u64, u64);
+        fn try_call_with_correlation(target: u64, request: u64) -> Result<(u64, u64), ActorError>;
+        fn call_timeout(target: u64, request: u64, rounds: u64) -> Option<u64>;
+        fn receive_correlated() -> (u64, u64);
+        fn reply_correlated(corr: u64, response: u64);
+        fn als_set(value: u64);
+        fn als_get() -> Option<u64>;
+        fn become_fn(next: Entry) -> !;
+    }
+}
+
+// 受信待ち状態の thread を CONTEXTS に戻す; 受信待ちでなければ false
+unsafe fn wake(key: u64) -> bool {
+    if let Some(ctx) = (*WAITING).remove(&key) {
+        #[cfg(debug_assertions)]
+        if let Some(waker) = CONTEXTS.front() {
+            (*WOKEN_BY).insert(key, waker.thread_id);
+        }
+        CONTEXTS.push_back(ctx);
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(feature = "global_runtime")]
+pub fn receive() -> Option<u64> {
+    receive_impl()
+}
+
+/*  receive と同じだが、取り出した後に自分の mailbox に残っている message 数も返す
+    batch 処理で、続けて処理するか yield するかを決めるのに使う (mailbox を引き直さずに済む)
+*/
+actor_api! {
+    pub fn receive_with_depth() -> Option<(u64, usize)> {
+        let msg = receive_impl()?;
+        unsafe {
+            let key = running("receive_with_depth").thread_id;
+            Some((msg, (*MESSAGES).len(key)))
+        }
+    }
+}
+
+fn receive_impl() -> Option<u64> {
+    unsafe {
+        let key = running("receive").thread_id;
+        #[cfg(debug_assertions)]
+        let mut parks: usize = 0;
+
+        loop {
+            // 大量の message が届き続けても CPU を独占しないよう、budget を使い切ったら一度 yield
+            if (*MESSAGES).len(key) > 0 && running("receive").recv_streak >= CONFIG.receive_budget {
+                running("receive").recv_streak = 0;
+                yield_now();
+            }
+
+            enter_critical("receive");
+            let popped = (*MESSAGES).pop_front(key);
+            IN_SCHEDULER = false;
+            if let Some(env) = popped {     // message がすでに queue に存在する
+                let msg = on_dequeue(key, env);
+                running("receive").recv_streak += 1;
+                safe_point();
+                return Some(msg);
+            }   // 以下、message が queue に存在しない
+            running("receive").recv_streak = 0;
+
+            if SHUTTING_DOWN {      // shutdown 中は受信待ちせずに None
+                return None;
+            }
+
+            if CONTEXTS.len() == 1 && BLOCKING_PENDING == 0 {   // 実行可能スレッドがほかに存在しない
+                match CONFIG.on_deadlock {
+                    DeadlockAction::Panic => {},    // park が deadlock として扱う
+                    DeadlockAction::ReturnNone => return None,
+                    DeadlockAction::Park(rounds) => {
+                        if wait_external(rounds) {
+                            continue;
+                        }
+                        panic!("deadlock: no external wakeup within {} round(s)", rounds);
+                    },
+                }
+            }
+
+            // message のないまま起こされ続けるなら起床処理の不具合: 止まらない loop にせず診断を出す
+            #[cfg(debug_assertions)]
+            {
+                if parks > CONFIG.max_reparks {
+                    let waker = (*WOKEN_BY).get(&key).map_or("unknown".to_string(), |id| id.to_string());
+                    panic!("receive: actor {} re-parked {} times without a message (last woken by {})", key, parks - 1, waker);
+                }
+                parks += 1;
+            }
+
+            // 受信待ち状態にする; 起床後に message が他の経路で消費済みなら再び受信待ちへ (疑似覚醒対策)
+            park_for(WaitReason::Message);
+        }
+    }
+}
+
+/*  worker pool: 同じ関数の worker を n 個生成し、job を round-robin で配る
+    worker は receive の代わりに pool_receive を使う -> 自分の mailbox が空なら、最も backlog の多い sibling から古い job を奪う
+    処理の速い worker が遅い worker の分を引き受けるため、配分の偏りがならされる
+*/
+struct WorkerPool {
+    workers: Vec<u64>,
+    next: usize,        // 次に job を配る worker の位置
+}
+
+actor_api! {
+    pub fn spawn_pool(func: Entry, stack_size: usize, n: usize) -> u64 {
+        assert!(n > 0, "spawn_pool: a pool needs at least one worker");
+        unsafe {
+            NEXT_POOL += 1;
+            let pool = NEXT_POOL;
+            let mut workers = Vec::with_capacity(n);
+            for _ in 0..n {
+                let id = get_id();
+                let ctx = new_context(spawn_entry(func), stack_size, id).unwrap_or_else(|e| spawn_failed(e));
+                CONTEXTS.push_back(ctx);
+                (*POOL_OF).insert(id, pool);
+                workers.push(id);
+            }
+            (*POOLS).insert(pool, WorkerPool { workers, next: 0 });
+            spawned();
+            pool
+        }
+    }
+}
+
+pub fn pool_workers(pool: u64) -> Vec<u64> {
+    unsafe {
+        if POOLS.is_null() {
+            return Vec::new();
+        }
+        (*POOLS).get(&pool).map_or(Vec::new(), |p| p.workers.clone())
+    }
+}
+
+// job を次の生存中の worker に送る; 受信待ちの sibling も起こして奪えるようにする
+actor_api! {
+    pub fn pool_submit(pool: u64, job: u64) {
+        unsafe {
+            let p = (*POOLS).get_mut(&pool).expect("pool_submit: unknown pool");
+            let n = p.workers.len();
+            let target = (0..n).map(|i| p.workers[(p.next + i) % n]).find(|id| is_alive(*id));
+            let target = match target {
+                Some(id) => id,
+                None => panic!("pool_submit: every worker of pool {} has exited", pool),
+            };
+            p.next = (p.workers.iter().position(|id| *id == target).unwrap() + 1) % n;
+
+            deliver(target, job);
+            let idle = p.workers.iter().copied().find(|id| *id != target && (*WAITING).contains_key(id) && (*MESSAGES).len(*id) == 0);
+            if let Some(id) = idle {
+                wake(id);
+            }
+        }
+        after_deliver("pool_submit");
+    }
+}
+
+// pool の worker 用の receive: 自分の mailbox、次に最も backlog の多い sibling の mailbox の順に取り出す
+actor_api! {
+    pub fn pool_receive() -> Option<u64> {
+        unsafe {
+            let key = running("pool_receive").thread_id;
+            let pool = *(*POOL_OF).get(&key).expect("pool_receive: caller is not a pool worker");
+
+            loop {
+                if let Some(env) = (*MESSAGES).pop_front(key) {
+                    return Some(on_dequeue(key, env));
+                }
+
+                let busiest = (*POOLS).get(&pool).unwrap().workers.iter().copied()
+                    .filter(|id| *id != key)
+                    .max_by_key(|id| (*MESSAGES).len(*id))
+                    .filter(|id| (*MESSAGES).len(*id) > 0);
+                if let Some(victim) = busiest {
+                    let env = (*MESSAGES).pop_front(victim).unwrap();
+                    return Some(on_dequeue(victim, env));       // 奪った message 自体の ack_id で ack する
+                }
+
+                if SHUTTING_DOWN {
+                    return None;
+                }
+                park_for(WaitReason::Message);
+            }
+        }
+    }
+}
+
+/*  barrier: participants 個の actor が到着するまで、到着した actor を受信待ち状態で待たせ、揃ったら全員を実行可能にする
+    同じ barrier_id は次の phase でも繰り返し使える
+    一度でも到着した actor (参加者) が終了すると barrier は壊れ、待っている actor と以降の到着は Err(BarrierBroken) になる
+    (一度も到着していない参加者の終了は検出できず、残りの actor は deadlock として検出される)
+*/
+struct Barrier {
+    participants: usize,
+    arrived: Vec<u64>,      // 現在の phase で到着済みの thread_id
+    members: HashSet<u64>,  // これまでに到着したことのある thread_id
+    phase: u64,             // 揃った phase の数
+    broken: bool,
+}
+
+actor_api! {
+    pub fn barrier(barrier_id: u64, participants: usize) -> Result<(), ActorError> {
+        assert!(participants > 0, "barrier: participants must be at least 1");
+        unsafe {
+            let key = current_id();
+            let b = (*BARRIERS).entry(barrier_id).or_insert_with(|| Barrier {
+                participants, arrived: Vec::new(), members: HashSet::new(), phase: 0, broken: false,
+            });
+            if b.broken {
+                return Err(ActorError::BarrierBroken(barrier_id));
+            }
+            if b.arrived.is_empty() {
+                b.participants = participants;      // phase の最初の到着者が人数を決める
+            }
+            assert!(b.participants == participants,
+                "barrier {}: participants mismatch ({} vs {})", barrier_id, b.participants, participants);
+
+            b.members.insert(key);
+            b.arrived.push(key);
+            if b.arrived.len() == participants {    // 全員揃った -> 全員を起こして次の phase へ
+                b.phase += 1;
+                for id in std::mem::take(&mut b.arrived) {
+                    wake(id);
+                }
+                return Ok(());
+            }
+
+            let phase = b.phase;
+            loop {
+                let b = (*BARRIERS).get(&barrier_id).unwrap();
+                if b.phase != phase {       // 自分の phase が揃って解放された (その後に参加者が終了して壊れても成功)
+                    return Ok(());
+                }
+                if b.broken {
+                    return Err(ActorError::BarrierBroken(barrier_id));
+                }
+                park_for(WaitReason::Barrier(barrier_id));
+            }
+        }
+    }
+}
+
+// 参加者 id の終了: id が参加している barrier を壊し、待っている actor を起こす
+unsafe fn break_barriers(id: u64) {
+    for b in (*BARRIERS).values_mut() {
+        if b.members.contains(&id) {
+            b.broken = true;
+            for waiter in b.arrived.drain(..) {
+                wake(waiter);
+            }
+        }
+    }
+}
+
+// 他の actor から receive_cancellable を中断させるための token
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CancelToken(u64);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        unsafe {
+            NEXT_TOKEN += 1;
+            CancelToken(NEXT_TOKEN)
+        }
+    }
+}
+
+// receive と同じだが、token が cancel されると (受信待ちの途中でも) None を返す; shutdown 中も None
+actor_api! {
+    pub fn receive_cancellable(token: CancelToken) -> Option<u64> {
+        unsafe {
+            let key = current_id();
+
+            loop {
+                if (*CANCELLED).contains(&token.0) {
+                    (*TOKEN_WAITERS).remove(&token.0);
+                    return None;
+                }
+                if let Some(env) = (*MESSAGES).pop_front(key) {
+                    (*TOKEN_WAITERS).remove(&token.0);
+                    return Some(on_dequeue(key, env));
+                }
+                if SHUTTING_DOWN {      // receive と同じく shutdown 中は受信待ちせずに None
+                    (*TOKEN_WAITERS).remove(&token.0);
+                    return None;
+                }
+
+                (*TOKEN_WAITERS).insert(token.0, key);
+                park_for(WaitReason::Message);
+            }
+        }
+    }
+}
+
+// token を cancel し、その token で受信待ちしている actor を起こす
+actor_api! {
+    pub fn cancel(token: CancelToken) {
+        unsafe {
+            (*CANCELLED).insert(token.0);
+            if let Some(waiter) = (*TOKEN_WAITERS).remove(&token.0) {
+                wake(waiter);
+            }
+        }
+    }
+}
+
+// receive した message ごとに f を呼ぶ; f が Break を返すか、shutdown() で receive が None を返したら終了
+actor_api! {
+    pub fn run_loop<F: FnMut(u64) -> ControlFlow<()>>(mut f: F) {
+        while let Some(msg) = receive_impl() {
+            if f(msg).is_break() {
+                break;
+            }
+        }
+    }
+}
+
+// runtime の終了を要求: 受信待ちの actor をすべて起こし、以後 mailbox が空の receive は None を返す
+actor_api! {
+    pub fn shutdown() {
+        unsafe {
+            SHUTTING_DOWN = true;
+            let waiting: Vec<u64> = (*WAITING).keys().copied().collect();
+            for id in waiting {
+                wake(id);
+            }
+        }
+    }
+}
+
+// park の理由を記録してから park する (waiting_detail 用); 起床したら記録を消す
+unsafe fn park_for(reason: WaitReason) {
+    let key = current_id();
+    (*WAIT_REASONS).insert(key, reason);
+    park();
+    (*WAIT_REASONS).remove(&key);
+}
+
+// 実行中の thread を受信待ち状態 (WAITING) にし、コンテキストスイッチ; WAITING から取り出されるまで戻らない
+unsafe fn park() {
+    tick();
+    wake_blocked();
+    if (*BLOCKING_RESULTS).contains_key(&CONTEXTS.front().unwrap().thread_id) {
+        return;     // 自分の run_blocking の結果がいま届いた: park せずに戻り、呼び出し側の loop で受け取る
+    }
+    if CONTEXTS.len() == 1 && BLOCKING_PENDING == 0 {    // 実行可能スレッドがほかに存在しない -> deadlock    
+        let key = current_id();
+        match if CONFIG.break_wait_cycles { find_wait_cycle(key) } else { None } {
+            Some(victim) => {
+                (*BROKEN).insert(victim);       // victim の待機ループが WaitCycleBroken を返す
+                if victim == key {
+                    return;     // 自分の待機を打ち切る: park せずに戻る
+                }
+                wake(victim);
+            },
+            None => panic!("deadlock"),     // 実際の設計ではタイムアウトを設けて処理
+        }
+    }
+
+    let mut ctx = CONTEXTS.pop_front().unwrap();
+    let key = ctx.thread_id;
+    ctx.slices_left = ctx.weight - 1;
+    let regs = ctx.get_regs_mut();
+    (*WAITING).insert(key, ctx);
+    while CONTEXTS.is_empty() {     // 実行可能スレッドがない -> OS スレッドでの処理の完了を待つ
+        wait_blocked();
+    }
+    if set_context(regs) == 0 {
+        select_next();
+        let next = CONTEXTS.front().unwrap();
+        switch_context((**next).get_regs());
+    }   // return しない
+
+    rm_unused_stack();
+}
+
+/*  request を送り、yield せずにそのまま自分の mailbox で受信待ちする (correlation id を使わない単純な request/reply)
+    send + receive では send の yield により、受信待ちになる前に自分が再び実行可能として scheduling される
+    ここでは配送・起床と受信待ちの間に schedule() を挟まないため、その間に reply が届く (取りこぼす) 余地がない
+    返り値は次に届いた message (target からの reply とは限らない)
+*/
+actor_api! {
+    pub fn send_then_receive(target: u64, request: u64) -> u64 {
+        unsafe {
+            deliver(target, request);       // enqueue と起床のみ
+        }
+        receive_impl().expect("send_then_receive: no reply (runtime is shutting down or deadlocked)")
+    }
+}
+
+/*  correlation id による request/reply: 送信元の thread_id ではなく corr_id で reply を振り分ける
+    caller: call_with_correlation(server, request) -> (corr_id, response)
+    server: let (corr_id, request) = receive_correlated(); ... reply_correlated(corr_id, response);
+*/
+actor_api! {
+    pub fn call_with_correlation(target: u64, request: u64) -> (u64, u64) {
+        try_call_with_correlation(target, request).unwrap_or_else(|e| panic!("call_with_correlation: {}", e))
+    }
+}
+
+// call_with_correlation と同じだが、break_wait_cycles により待機を打ち切られた場合は Err(WaitCycleBroken)
+// 打ち切られた後に届いた reply は捨てられる
+actor_api! {
+    pub fn try_call_with_correlation(target: u64, request: u64) -> Result<(u64, u64), ActorError> {
+        unsafe {
+            let key = current_id();
+            let corr = post_call(target, request);
+
+            loop {
+                if let Some(response) = (*REPLIES).remove(&corr) {
+                    (*WAITING_ON).remove(&key);
+                    return Ok((corr, response));
+                }
+                if (*BROKEN).remove(&key) {
+                    (*WAITING_ON).remove(&key);
+                    (*PENDING_REPLIES).remove(&corr);
+                    return Err(ActorError::WaitCycleBroken);
+                }
+                (*WAITING_ON).insert(key, target);
+                park_for(WaitReason::Reply(target));     // reply 以外で起床しても再び待つ
+            }
+        }
+    }
+}
+
+// corr_id を発行して (corr_id, request) を target に送る; reply は PENDING_REPLIES に登録した自分に届く
+unsafe fn post_call(target: u64, request: u64) -> u64 {
+    NEXT_CORR += 1;
+    let corr = NEXT_CORR;
+    (*PENDING_REPLIES).insert(corr, current_id());
+
+    // corr_id, request の順に続けて配送; deliver は yield しないので他の message が割り込まない
+    // send hook や mailbox の上限で corr_id が捨てられた場合は request も送らない (reply は来ない)
+    if deliver(target, corr).0.is_some() {
+        deliver(target, request);
+    }
+    corr
+}
+
+// call_with_correlation と同じだが、rounds 回 yield しても reply がなければ諦めて None
+// 諦めた後に届いた reply は (待っている caller がいないため) 捨てられる
+actor_api! {
+    pub fn call_timeout(target: u64, request: u64, rounds: u64) -> Option<u64> {
+        unsafe {
+            let corr = post_call(target, request);
+            for _ in 0..rounds {
+                if let Some(response) = (*REPLIES).remove(&corr) {
+                    return Some(response);
+                }
+                yield_now();
+            }
+            (*PENDING_REPLIES).remove(&corr);     // 以降の reply_correlated は caller を見つけられず捨てる
+            (*REPLIES).remove(&corr)
+        }
+    }
+}
+
+// call_with_correlation で送られた (corr_id, request) を受信
+actor_api! {
+    pub fn receive_correlated() -> (u64, u64) {
+        let corr = receive_impl().unwrap();
+        let request = receive_impl().unwrap();
+        (corr, request)
+    }
+}
+
+// corr_id に対応する caller だけを起床させる; 対応する caller がいなければ reply は捨てる
+actor_api! {
+    pub fn reply_correlated(corr: u64, response: u64) {
+        unsafe {
+            if let Some(caller) = (*PENDING_REPLIES).remove(&corr) {
+                (*REPLIES).insert(corr, response);
+                wake(caller);
+            }
+        }
+        after_deliver("reply_correlated");
+    }
+}
+
+// 長時間ブロックする同期処理 f を OS スレッドで実行し、完了するまでこの thread だけを受信待ち状態にする
+// (f を直接呼ぶと runtime 全体が止まる)
+actor_api! {
+    pub fn run_blocking<F>(f: F) -> u64
+    where
+        F: FnOnce() -> u64 + Send + 'static,
+    {
+        unsafe {
+            let key = current_id();
+            BLOCKING_PENDING += 1;
+            thread::spawn(move || {
+                let result = f();
+                BLOCKING_DONE.lock().unwrap().push((key, result));
+                BLOCKING_CV.notify_one();
+            });
+
+            loop {
+                if let Some(result) = (*BLOCKING_RESULTS).remove(&key) {
+                    return result;
+                }
+                park_for(WaitReason::Blocking);     // message 受信などで起床しても、結果が届くまでは再び待つ
+            }
+        }
+    }
+}
+
+// 完了した OS スレッドの結果を受け取り、待っている thread を CONTEXTS に戻す
+unsafe fn wake_blocked() {
+    drain_external();
+    if BLOCKING_PENDING == 0 {
+        return;
+    }
+
+    let done = std::mem::take(&mut *BLOCKING_DONE.lock().unwrap());
+    for (key, result) in done {
+        BLOCKING_PENDING = BLOCKING_PENDING.saturating_sub(1);     // 前の session で kill された thread の結果が遅れて届く場合がある
+        (*BLOCKING_RESULTS).insert(key, result);
+        if let Some(ctx) = (*WAITING).remove(&key) {
+            CONTEXTS.push_back(ctx);
+        }
+    }
+}
+
+/*  runtime の外 (別の OS スレッド) から actor に message を送る; 次の schedule / park で mailbox に移される
+    deadlock 時の DeadlockAction::Park は、これによる起床を待つ
+*/
+pub fn send_external(key: u64, msg: u64) {
+    EXTERNAL.lock().unwrap().push((key, msg));
+    EXTERNAL_PENDING.store(true, Ordering::Release);
+    BLOCKING_CV.notify_one();
+}
+
+unsafe fn drain_external() {
+    if !EXTERNAL_PENDING.swap(false, Ordering::Acquire) {
+        return;
+    }
+    let external = std::mem::take(&mut *EXTERNAL.lock().unwrap());
+    for (key, msg) in external {
+        (*MESSAGES).push_back(key, msg);
+        wake(key);
+    }
+}
+
+// deadlock 時に外部からの send_external を最大 rounds 回待つ; 実行可能になったら true
+unsafe fn wait_external(rounds: u32) -> bool {
+    let key = current_id();
+    for _ in 0..rounds {
+        let done = BLOCKING_DONE.lock().unwrap();
+        if !EXTERNAL_PENDING.load(Ordering::Acquire) {
+            let _ = BLOCKING_CV.wait_timeout(done, DEADLOCK_ROUND).unwrap();
+        } else {
+            drop(done);
+        }
+        wake_blocked();
+        if CONTEXTS.len() > 1 || (*MESSAGES).len(key) > 0 {
+            return true;
+        }
+    }
+    false
+}
+
+// OS スレッドの処理がどれか完了するまで、この OS スレッドごとブロック
+unsafe fn wait_blocked() {
+    let mut done = BLOCKING_DONE.lock().unwrap();
+    while done.is_empty() {
+        done = BLOCKING_CV.wait(done).unwrap();
+    }
+    drop(done);
+    wake_blocked();
+}
+
+// actor-local storage: 実行中の actor の Context に値を保存
+actor_api! {
+    pub fn als_set(value: u64) {
+        unsafe {
+            running("als_set").local = Some(value);
+        }
+    }
+}
+
+actor_api! {
+    pub fn als_get() -> Option<u64> {
+        unsafe {
+            running("als_get").local
+        }
+    }
+}
+
+// entry_point 関数
+extern "C" fn entry_point() {
+    unsafe {
+        if CTX_MAIN.is_none() {     // actor が動いている間は main() のコンテキストが必ず存在するはず
+            eprintln!("entry_point: actor is running without an active runtime (CTX_MAIN is None)");
+            process::abort();
+        }
+
+        let entry = CONTEXTS.front_mut().unwrap().entry.take().unwrap();
+        // thread の entry 関数実行; panic は extern "C" fn の外へ unwind させずここで捕捉
+        let reason = match panic::catch_unwind(AssertUnwindSafe(entry)) {
+            Ok(result) => ExitReason::Normal(result),
+            Err(payload) if payload.is::<ActorFailure>() => {      // spawn_try の Err: panic ではないので報告しない
+                ExitReason::Error(payload.downcast::<ActorFailure>().unwrap().0)
+            },
+            Err(payload) => {
+                report_panic(current_id(), &*payload);
+                ExitReason::Panicked
+            },
+        };
+        // entry() の終了 <=> thread の終了
+        terminate(reason);
+    }
+}
+
+/*  実行中の actor の振る舞いを next に置き換える (Erlang の become): id, mailbox, link, 優先度などはそのまま
+    スタックを最上位から使い直して entry_point から next を実行する -> 終了・再 spawn は行わない
+    現在のスタック上のローカル変数は drop されずに捨てられる (heap を持つ値は leak する)
+*/
+actor_api! {
+    pub fn become_fn(next: Entry) -> ! {
+        unsafe {
+            let ctx = running("become_fn");
+            let top = ctx.stack as u64 + ctx.stack_layout.size() as u64;
+            ctx.regs = Registers::new(top - RED_ZONE as u64, entry_point as extern "C" fn() as usize as u64);
+            ctx.entry = Some(from_entry(next));
+            ctx.recv_streak = 0;
+            switch_context(ctx.get_regs());     // 自分自身へのコンテキストスイッチ: sp が最上位に戻り entry_point へ
+        }
+    }
+}
+
+// actor の panic を (actor の id, panic の payload) で受け取る handler; 一度に一つだけ登録できる
+type PanicHandler = Box<dyn Fn(u64, &dyn Any)>;
+static mut PANIC_HANDLER: Option<PanicHandler> = None;
+
+pub fn set_panic_handler(f: PanicHandler) {
+    unsafe {
+        PANIC_HANDLER = Some(f);
+    }
+}
+
+// 登録された handler へ panic を通知; 未登録なら stderr に出力
+unsafe fn report_panic(id: u64, payload: &dyn Any) {
+    match &PANIC_HANDLER {
+        Some(handler) => {
+            // handler 自身の panic も extern "C" fn の外へ unwind させない
+            if panic::catch_unwind(AssertUnwindSafe(|| handler(id, payload))).is_err() {
+                eprintln!("actor {}: panic handler panicked", id);
+            }
+        },
+        None => {
+            let msg = payload.downcast_ref::<&str>().copied()
+                .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+                .unwrap_or("<non-string payload>");
+            eprintln!("actor {} panicked: {}", id, msg);
+        }
+    }
+}
+
+// 実行中の thread を終了させ、次のスレッド (なければ main()) にコンテキストスイッチ
+unsafe fn terminate(reason: ExitReason) -> ! {
+    IN_SCHEDULER = false;       // critical section 中の panic (custom mailbox など) で終了した場合
+    propagate_exit(current_id(), &reason);      // 異常終了ならリンク先も終了させる (自分がまだ先頭にいる間に行う)
+
+    let ctx = CONTEXTS.pop_front().unwrap();
+    let id = ctx.thread_id;
+    release_id(id);
+    if ctx.owns_stack {
+        UNUSED_STACK.push((ctx.stack, ctx.stack_layout, ctx.guard_size));     // 自分のスタック上で動いているため、コンテキストスイッチ後に解放するよう予約
+    }
+    recycle(ctx);
+    record_exit(id, reason);    // join している thread を起こす
+    while CONTEXTS.is_empty() && BLOCKING_PENDING > 0 {     // run_blocking 中の thread が残っていれば main() に戻らず待つ
+        wait_blocked();
+    }
+    if CONTEXTS.is_empty() {
+        wake_reaper();      // on_empty が RunActor なら main() に戻る前に実行する
+    }
+
+    select_next();
+    match CONTEXTS.front() {        // 次のスレッドにコンテキストスイッチ
+        Some(c) => {
+            switch_context((**c).get_regs());
+        },
+        None => {       // main() へコンテキストスイッチ
+            if let Some(c) = &CTX_MAIN {
+                switch_context(&**c as *const Registers);
+            }
+        }
+    };
+
+    // 到達しないはず; extern "C" fn から panic で unwind すると未定義動作なので abort する
+    eprintln!("entry_point: no context to switch to (CONTEXTS and CTX_MAIN are both empty)");
+    process::abort();
+}
+
+// 他に実行可能な actor がなくなったとき、on_empty で指定された actor (受信待ち・停止中) を shutdown 中として起こす
+unsafe fn wake_reaper() {
+    let id = match CONFIG.on_empty {
+        OnEmpty::RunActor(id) => id,
+        OnEmpty::ReturnToMain => return,
+    };
+    let ctx = match (*WAITING).remove(&id) {
+        Some(ctx) => ctx,
+        None => match (*SUSPENDED).remove(&id) {
+            Some(ctx) => ctx,
+            None => return,
+        },
+    };
+    SHUTTING_DOWN = true;       // 起こした後の receive は None を返す (送ってくる actor はもういない)
+    CONTEXTS.push_back(ctx);
+}
+
+// ContextBox は次の spawn で再利用 (ここで捨てると switch_context で戻らないためリークする)
+unsafe fn recycle(ctx: ContextBox) {
+    if CONTEXT_POOL.len() < CONTEXT_POOL_MAX {
+        CONTEXT_POOL.push(ctx);
+    }
+}
+
+/*  スタックオーバーフローの回収 (AArch64 Linux のみ)
+    ガードページへのアクセスで発生した SIGSEGV を sigaltstack 上の handler で受け取り、
+    実行中の actor のガードページ内であれば、signal から戻る先 (pc) を stack_overflow_exit に、sp をその actor のスタック最上位に書き換える
+    -> sigreturn により signal mask と代替スタックの状態が元に戻り、その actor だけが ExitReason::StackOverflow で終了する
+    溢れた時点の actor のフレームは drop されずに捨てられる (heap の leak, 解放されない lock などがあり得る)
+    実行中の actor のガードページ以外での SIGSEGV は、install 前に登録されていた handler (PREV_SEGV) に渡す
+    -> 以前の handler が関数ならそのまま呼び、SIG_DFL / SIG_IGN なら以前の設定に戻して faulting 命令を再実行させる (プロセス終了)
+    teardown で以前の設定に戻す
+*/
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+const ALT_STACK_SIZE: usize = 4 * libc::SIGSTKSZ;
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+static mut ALT_STACK: [u8; ALT_STACK_SIZE] = [0; ALT_STACK_SIZE];
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+static mut PREV_SEGV: Option<libc::sigaction> = None;      // install_overflow_handler の前に登録されていた SIGSEGV の設定
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+fn install_overflow_handler() {
+    unsafe {
+        // 代替スタックは OS スレッドごとの設定なので、runtime を動かすスレッドで毎回設定する
+        let alt = libc::stack_t {
+            ss_sp: ptr::addr_of_mut!(ALT_STACK) as *mut libc::c_void,
+            ss_flags: 0,
+            ss_size: ALT_STACK_SIZE,
+        };
+        if libc::sigaltstack(&alt, ptr::null_mut()) != 0 {
+            eprintln!("warning: sigaltstack failed; stack overflow will abort the process");
+            return;
+        }
+
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = segv_handler as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut prev: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGSEGV, &action, &mut prev) != 0 {
+            eprintln!("warning: sigaction(SIGSEGV) failed; stack overflow will abort the process");
+            return;
+        }
+        // teardown されずに再び install された場合は自分自身を以前の handler として保存しない
+        if prev.sa_sigaction != action.sa_sigaction {
+            PREV_SEGV = Some(prev);
+        }
+    }
+}
+
+// install_overflow_handler の前の SIGSEGV の設定に戻す
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+fn restore_overflow_handler() {
+    unsafe {
+        if let Some(prev) = PREV_SEGV.take() {
+            libc::sigaction(libc::SIGSEGV, &prev, ptr::null_mut());
+        }
+    }
+}
+
+// 回収できない環境では何もしない (ガードページに触れるとプロセスが落ちる)
+#[cfg(not(all(target_arch = "aarch64", target_os = "linux")))]
+fn install_overflow_handler() {}
+
+#[cfg(not(all(target_arch = "aarch64", target_os = "linux")))]
+fn restore_overflow_handler() {}
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+extern "C" fn segv_handler(sig: libc::c_int, info: *mut libc::siginfo_t, uctx: *mut libc::c_void) {
+    unsafe {
+        let addr = (*info).si_addr() as usize;
+        let uctx = &mut *(uctx as *mut libc::ucontext_t);
+
+        if CTX_MAIN.is_some() {
+            if let Some(ctx) = CONTEXTS.front() {
+                let guard = ctx.stack as usize;
+                if addr >= guard && addr < guard + ctx.guard_size {
+                    let top = (ctx.stack as usize + ctx.stack_layout.size()) & !15;    // AAPCS64: sp は 16 バイト境界
+                    uctx.uc_mcontext.sp = top as u64;
+                    uctx.uc_mcontext.pc = stack_overflow_exit as extern "C" fn() -> ! as usize as u64;
+                    uctx.uc_mcontext.regs[30] = 0;      // stack_overflow_exit は return しない
+                    return;
+                }
+            }
+        }
+
+        // 回収できない fault: 以前の handler に渡す
+        match PREV_SEGV {
+            Some(prev) if prev.sa_sigaction != libc::SIG_DFL && prev.sa_sigaction != libc::SIG_IGN => {
+                if prev.sa_flags & libc::SA_SIGINFO != 0 {
+                    let f: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) = std::mem::transmute(prev.sa_sigaction);
+                    f(sig, info, uctx as *mut libc::ucontext_t as *mut libc::c_void);
+                } else {
+                    let f: extern "C" fn(libc::c_int) = std::mem::transmute(prev.sa_sigaction);
+                    f(sig);
+                }
+            },
+            // 以前の設定に戻して faulting 命令を再実行 -> 通常どおり SIGSEGV で終了 (同期的な fault は SIG_IGN でも無視されない)
+            Some(prev) => {
+                libc::sigaction(libc::SIGSEGV, &prev, ptr::null_mut());
+            },
+            None => {
+                libc::signal(libc::SIGSEGV, libc::SIG_DFL);
+            },
+        }
+    }
+}
+
+// signal handler から戻った先: 溢れた actor 自身のスタック (最上位から使い直す) 上で動く
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+extern "C" fn stack_overflow_exit() -> ! {
+    unsafe {
+        // handler 内では HashMap を触れない (async-signal-safe でない) ため、戻った先で記録する
+        *(*GUARD_FAULTS).entry(current_id()).or_insert(0) += 1;
+        eprintln!("actor {} overflowed its stack", current_id());
+        terminate(ExitReason::StackOverflow);
+    }
+}
+
+/*  test: runtime は global 変数を使うため、serial() で 1 つずつ実行する
+    コンテキストスイッチは asm/context.s (AArch64) が必要なため、AArch64 でのみ実行する
+*/
+#[cfg(all(test, target_arch = "aarch64"))]
+mod tests {
+    use super::*;
+    // explicit_runtime のみの場合も、test は free function の名前で書く (他の actor API は green module 内から見える)
+    #[cfg(not(feature = "global_runtime"))]
+    #[allow(unused_imports)]
+    use super::{receive_impl as receive, send_impl as send, spawn_impl as spawn};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Mutex, MutexGuard};
+
+    const STACK: usize = 64 * 1024;
+    const ROUNDS: u64 = 100;
+
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    // test の終了時 (失敗した場合も) に設定と hook を既定に戻す
+    struct Serial {
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    // スタックの確保 (ページ境界へのアライメント) を数える global allocator; assert_no_leaks が未解放の数を調べる
+    struct CountingAlloc;
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let p = std::alloc::System.alloc(layout);
+            if !p.is_null() && layout.align() >= PAGE_SIZE {
+                LIVE_STACK_ALLOCS.fetch_add(1, Ordering::SeqCst);
+            }
+            p
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            if layout.align() >= PAGE_SIZE {
+                LIVE_STACK_ALLOCS.fetch_sub(1, Ordering::SeqCst);
+            }
+            std::alloc::System.dealloc(ptr, layout);
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: CountingAlloc = CountingAlloc;
+
+    impl Drop for Serial {
+        fn drop(&mut self) {
+            unsafe {
+                CONFIG = RuntimeConfig::new();
+                PLATFORM = &LinuxPlatform;
+                SEND_HOOK = None;
+                PANIC_HANDLER = None;
+                ON_SCHEDULE = None;
+            }
+        }
+    }
+
+    fn serial() -> Serial {
+        Serial { _lock: SERIAL.lock().unwrap_or_else(|e| e.into_inner()) }
+    }
+
+    /*  test 用の Platform: 既定では LinuxPlatform にそのまま転送し、呼び出しの回数だけを数える
+        MOCK_* の flag で個々の処理を差し替える; mock_platform() が回数と flag を 0 / false に戻してから返す
+        FAIL_PROTECT / FAIL_UNPROTECT: EACCES で失敗させる
+        NO_SYSCALLS: protect / unprotect で mprotect を呼ばずに成功する (ガードページは実際には保護しない)
+        BUMP_CONTEXTS: Context を固定長の ARENA から切り出す (解放は数えるだけ)
+        REENTER_ON_FREE: スタックの解放中に rm_unused_stack に再入する (scheduling の不具合の再現)
+    */
+    static FAIL_PROTECT: AtomicBool = AtomicBool::new(false);
+    static FAIL_UNPROTECT: AtomicBool = AtomicBool::new(false);
+    static NO_SYSCALLS: AtomicBool = AtomicBool::new(false);
+    static BUMP_CONTEXTS: AtomicBool = AtomicBool::new(false);
+    static REENTER_ON_FREE: AtomicBool = AtomicBool::new(false);
+
+    static STACK_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+    static STACK_FREES: AtomicUsize = AtomicUsize::new(0);
+    static PROTECTS: AtomicUsize = AtomicUsize::new(0);
+    static UNPROTECTS: AtomicUsize = AtomicUsize::new(0);
+    static CONTEXT_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+    static CONTEXT_FREES: AtomicUsize = AtomicUsize::new(0);
+
+    #[repr(align(64))]
+    struct Arena([u8; 256 * 1024]);
+
+    static mut ARENA: Arena = Arena([0; 256 * 1024]);
+    static ARENA_USED: AtomicUsize = AtomicUsize::new(0);
+
+    struct MockPlatform;
+
+    static MOCK: MockPlatform = MockPlatform;
+
+    fn mock_platform() -> &'static MockPlatform {
+        for flag in [&FAIL_PROTECT, &FAIL_UNPROTECT, &NO_SYSCALLS, &BUMP_CONTEXTS, &REENTER_ON_FREE] {
+            flag.store(false, Ordering::SeqCst);
+        }
+        for counter in [&STACK_ALLOCS, &STACK_FREES, &PROTECTS, &UNPROTECTS, &CONTEXT_ALLOCS, &CONTEXT_FREES, &ARENA_USED] {
+            counter.store(0, Ordering::SeqCst);
+        }
+        &MOCK
+    }
+
+    // 確保されたまま解放されていないスタックの数
+    fn mock_live_stacks() -> usize {
+        STACK_ALLOCS.load(Ordering::SeqCst) - STACK_FREES.load(Ordering::SeqCst)
+    }
+
+    fn in_arena(p: *mut u8) -> bool {
+        let base = ptr::addr_of!(ARENA) as usize;
+        (base..base + std::mem::size_of::<Arena>()).contains(&(p as usize))
+    }
+
+    impl Platform for MockPlatform {
+        fn page_size(&self) -> usize {
+            LinuxPlatform.page_size()
+        }
+
+        unsafe fn alloc_stack(&self, layout: Layout) -> *mut u8 {
+            STACK_ALLOCS.fetch_add(1, Ordering::SeqCst);
+            LinuxPlatform.alloc_stack(layout)
+        }
+
+        unsafe fn dealloc_stack(&self, stack: *mut u8, layout: Layout) {
+            STACK_FREES.fetch_add(1, Ordering::SeqCst);
+            if REENTER_ON_FREE.load(Ordering::SeqCst) {
+                rm_unused_stack();
+            }
+            LinuxPlatform.dealloc_stack(stack, layout)
+        }
+
+        unsafe fn protect(&self, stack: *mut u8, len: usize) -> Result<(), Errno> {
+            PROTECTS.fetch_add(1, Ordering::SeqCst);
+            if FAIL_PROTECT.load(Ordering::SeqCst) {
+                return Err(Errno::EACCES);
+            }
+            if NO_SYSCALLS.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            LinuxPlatform.protect(stack, len)
+        }
+
+        unsafe fn unprotect(&self, stack: *mut u8, len: usize) -> Result<(), Errno> {
+            UNPROTECTS.fetch_add(1, Ordering::SeqCst);
+            if FAIL_UNPROTECT.load(Ordering::SeqCst) {
+                return Err(Errno::EACCES);
+            }
+            if NO_SYSCALLS.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            LinuxPlatform.unprotect(stack, len)
+        }
+
+        unsafe fn alloc_context(&self, layout: Layout) -> *mut u8 {
+            CONTEXT_ALLOCS.fetch_add(1, Ordering::SeqCst);
+            if !BUMP_CONTEXTS.load(Ordering::SeqCst) {
+                return LinuxPlatform.alloc_context(layout);
+            }
+            let offset = ARENA_USED.load(Ordering::SeqCst).next_multiple_of(layout.align());
+            if offset + layout.size() > std::mem::size_of::<Arena>() {
+                return ptr::null_mut();
+            }
+            ARENA_USED.store(offset + layout.size(), Ordering::SeqCst);
+            (ptr::addr_of_mut!(ARENA) as *mut u8).add(offset)
+        }
+
+        unsafe fn dealloc_context(&self, p: *mut u8, layout: Layout) {
+            CONTEXT_FREES.fetch_add(1, Ordering::SeqCst);
+            if !in_arena(p) {       // flag を切り替える前に確保した分は global allocator に返す
+                LinuxPlatform.dealloc_context(p, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn contexts_are_allocated_through_the_platform_hook() {
+        let _s = serial();
+        set_platform(mock_platform());
+        BUMP_CONTEXTS.store(true, Ordering::SeqCst);
+        spawn_from_main(|| {
+            for _ in 0..200 {
+                spawn(|| {}, STACK);        // spawn の schedule で実行され、終了して戻ってくる
+            }
+        }, STACK);
+        let allocs = CONTEXT_ALLOCS.load(Ordering::SeqCst);
+        assert!(allocs > 0);
+        assert!(allocs < 200, "finished contexts should be recycled instead of reallocated: {}", allocs);
+        assert_eq!(allocs, CONTEXT_FREES.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn queries_outside_the_runtime_return_empty() {
+        let _s = serial();
+        assert_eq!(stack_range(1), None);
+        assert_eq!(run_count(1), 0);
+        assert_eq!(actor_age(1), None);
+        assert_eq!(parent_of(1), None);
+        assert_eq!(guard_faults(1), 0);
+        assert_eq!(message_stats(1), (0, 0));
+        assert_eq!(dropped_messages(1), 0);
+        assert_eq!(mailbox_len(1), 0);
+        assert!(pool_workers(1).is_empty());
+    }
+
+    static mut SERVER: u64 = 0;
+    static mut RESPONSES: [u64; 3] = [0; 3];
+
+    fn serve_three() {
+        for _ in 0..3 {
+            let (corr, request) = receive_correlated();
+            reply_correlated(corr, request * 10);
+        }
+    }
+
+    fn call(slot: usize) {
+        let (_, response) = call_with_correlation(unsafe { SERVER }, slot as u64 + 1);
+        unsafe { RESPONSES[slot] = response };
+    }
+
+    #[test]
+    fn correlated_replies_reach_their_own_caller() {
+        let _s = serial();
+        spawn_from_main(|| unsafe {
+            SERVER = spawn(serve_three, STACK);
+            spawn(|| call(0), STACK);
+            spawn(|| call(1), STACK);
+            spawn(|| call(2), STACK);
+        }, STACK);
+        assert_eq!(unsafe { RESPONSES }, [10, 20, 30]);
+    }
+
+    #[test]
+    fn correlated_calls_go_through_the_send_hook() {
+        let _s = serial();
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+        static REPLY: AtomicUsize = AtomicUsize::new(0);
+        SEEN.store(0, Ordering::SeqCst);
+        set_send_hook(Box::new(|_, msg| {
+            SEEN.fetch_add(1, Ordering::SeqCst);
+            Some(msg)
+        }));
+        spawn_from_main(|| {
+            let server = spawn_fn(|| {
+                let (corr, request) = receive_correlated();
+                reply_correlated(corr, request + 1);
+                0
+            }, STACK);
+            REPLY.store(call_with_correlation(server, 41).1 as usize, Ordering::SeqCst);
+            join(server);
+        }, STACK);
+        assert_eq!(REPLY.load(Ordering::SeqCst), 42);
+        assert_eq!(SEEN.load(Ordering::SeqCst), 2);    // corr_id と request
+    }
+
+    // 最小の executor: Waker は block_on を呼んだ OS スレッドを unpark する
+    struct ThreadWaker(thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(std::sync::Arc::new(ThreadWaker(thread::current())));
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    static HANDLE_TX: Mutex<Option<std::sync::mpsc::Sender<AsyncJoinHandle>>> = Mutex::new(None);
+
+    #[test]
+    fn actor_result_can_be_awaited_from_another_thread() {
+        let _s = serial();
+        let (tx, rx) = std::sync::mpsc::channel();
+        *HANDLE_TX.lock().unwrap() = Some(tx);
+        let runtime = thread::spawn(|| spawn_from_main(|| {
+            let (_, handle) = spawn_async(|| {
+                run_blocking(|| {
+                    thread::sleep(std::time::Duration::from_millis(50));     // executor 側が先に Pending を見る
+                    0
+                });
+                42
+            }, STACK);
+            HANDLE_TX.lock().unwrap().take().unwrap().send(handle).unwrap();
+        }, STACK));
+        let handle = rx.recv().unwrap();
+        assert_eq!(block_on(handle), 42);
+        runtime.join().unwrap();
+    }
+
+    #[test]
+    fn dropping_an_async_handle_frees_its_slot() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let (_, handle) = spawn_async(|| 1, STACK);
+            drop(handle);
+        }, STACK);
+        assert!(COMPLETIONS.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancel_wakes_a_cancellable_receive_with_none() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let token = CancelToken::new();
+            let waiter = spawn_fn(move || receive_cancellable(token).map_or(1, |_| 2), STACK);     // 受信待ちになって戻ってくる
+            cancel(token);
+            *EXITS.lock().unwrap() = vec![join(waiter)];
+        }, STACK);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(1)]);
+    }
+
+    static mut LOCALS: [Option<u64>; 2] = [None; 2];
+
+    fn local_roundtrip(slot: usize, value: u64) {
+        als_set(value);
+        for _ in 0..ROUNDS {
+            schedule();     // 相手の actor が als_set しても自分の値は変わらない
+            assert_eq!(als_get(), Some(value));
+        }
+        unsafe { LOCALS[slot] = als_get() };
+    }
+
+    #[test]
+    fn actor_locals_do_not_interfere() {
+        let _s = serial();
+        spawn_from_main(|| {
+            spawn(|| local_roundtrip(0, 1), STACK);
+            spawn(|| local_roundtrip(1, 2), STACK);
+            assert_eq!(als_get(), None);
+        }, STACK);
+        assert_eq!(unsafe { LOCALS }, [Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn registers_place_the_trampoline_in_the_link_register() {
+        extern "C" fn trampoline() {}
+        let entry = trampoline as extern "C" fn() as usize as u64;
+        let regs = Registers::new(0x1000, entry);
+        assert_eq!(regs.x30, entry);        // switch_context の ret で最初に飛ぶ先
+        assert_eq!(regs.sp, 0x1000);
+    }
+
+    static mut PARENT: u64 = 0;
+
+    fn sum_three() {
+        let total = (0..3).map(|_| receive().unwrap()).sum::<u64>();
+        send(unsafe { PARENT }, total);
+    }
+
+    #[test]
+    fn a_suspended_actor_runs_its_backlog_after_resume() {
+        let _s = serial();
+        spawn_from_main(|| unsafe {
+            PARENT = CONTEXTS.front().unwrap().thread_id;
+            let id = spawn_suspended(sum_three, STACK);
+            for i in 1..=3 {
+                send(id, i);
+            }
+            for _ in 0..ROUNDS {
+                schedule();
+            }
+            assert_eq!((&(*MESSAGES).map)[&id].len(), 3);      // 停止中は実行されない
+            assert!(resume(id));
+            assert_eq!(receive(), Some(6));
+        }, STACK);
+    }
+
+    #[test]
+    fn undrained_messages_are_counted_at_shutdown() {
+        let mut msgs = MappedList::new();
+        assert_eq!(warn_undrained(&msgs), 0);
+        for (key, msg) in [(1, 10), (1, 11), (2, 20)] {
+            msgs.push_back(key, msg);
+        }
+        assert_eq!(warn_undrained(&msgs), 3);
+    }
+
+    fn wait_one() {
+        receive();
+    }
+
+    #[test]
+    fn live_actors_tracks_spawns_and_exits() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let me = unsafe { CONTEXTS.front().unwrap().thread_id };
+            let workers: Vec<u64> = (0..3).map(|_| spawn(wait_one, STACK)).collect();
+            let mut expected = workers.clone();
+            expected.push(me);
+            expected.sort();
+            let mut live = live_actors();
+            live.sort();
+            assert_eq!(live, expected);
+            for (i, id) in workers.iter().enumerate() {
+                send(*id, 0);       // send の schedule で worker が受信して終了する
+                assert_eq!(live_actors().len(), 3 - i);     // 終了した分だけ減る
+                assert!(!live_actors().contains(id));
+            }
+            assert_eq!(live_actors(), vec![me]);
+        }, STACK);
+    }
+
+    static mut RECEIVED: u64 = 0;
+    static mut DURING_SENDS: u64 = 0;
+
+    fn drain_rounds() {
+        for _ in 0..ROUNDS {
+            receive();
+            unsafe { RECEIVED += 1 };
+        }
+    }
+
+    // ROUNDS 回の send の間に送信側へ切り替わった回数
+    // ROUNDS 回の send の間に受信側が処理した message の数
+    fn received_during_sends(send_yields: bool) -> u64 {
+        let mut config = RuntimeConfig::new();
+        config.send_yields = send_yields;
+        set_config(config);
+        unsafe { RECEIVED = 0 };
+        spawn_from_main(|| {
+            let sink = spawn(drain_rounds, STACK);
+            for i in 0..ROUNDS {
+                send(sink, i);
+            }
+            unsafe { DURING_SENDS = RECEIVED };
+        }, STACK);
+        unsafe { DURING_SENDS }
+    }
+
+    #[test]
+    fn send_yields_controls_context_switches() {
+        let _s = serial();
+        assert_eq!(received_during_sends(true), ROUNDS);     // send ごとに yield して受信側が動く
+        assert_eq!(received_during_sends(false), 0);        // 起床のみで送信側が実行を続ける
+    }
+
+    static mut GOT: Option<u64> = None;
+
+    fn wait_for_one() {
+        unsafe { GOT = receive() };
+    }
+
+    #[test]
+    fn a_waiter_woken_without_a_message_parks_again() {
+        let _s = serial();
+        spawn_from_main(|| unsafe {
+            GOT = None;
+            let id = spawn(wait_for_one, STACK);       // spawn の schedule で受信待ちになる
+            let ctx = (*WAITING).remove(&id).unwrap();
+            CONTEXTS.push_back(ctx);        // message なしで起こす (疑似覚醒)
+            schedule();
+            assert!((*WAITING).contains_key(&id));      // 再び受信待ちになっている
+            assert_eq!(GOT, None);
+            send(id, 7);
+            assert_eq!(GOT, Some(7));
+        }, STACK);
+    }
+
+    #[test]
+    fn run_blocking_alone_returns_its_result() {
+        let _s = serial();
+        spawn_from_main(|| {
+            for i in 0..ROUNDS {
+                assert_eq!(run_blocking(move || i), i);     // thread が park より先に終わっても deadlock と判定しない
+            }
+        }, STACK);
+    }
+
+    static mut BLOCKED_RESULT: u64 = 0;
+
+    #[test]
+    fn other_actors_keep_running_during_run_blocking() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let blocker = spawn(|| unsafe {
+                BLOCKED_RESULT = run_blocking(|| {
+                    thread::sleep(std::time::Duration::from_millis(50));
+                    1
+                });
+            }, STACK);
+            let mut steps = 0;
+            while live_actors().contains(&blocker) {       // 止まらずに進み続ける
+                steps += 1;
+                yield_now();
+            }
+            assert!(steps > 1);
+            assert_eq!(unsafe { BLOCKED_RESULT }, 1);
+        }, STACK);
+    }
+
+    #[test]
+    fn the_running_stack_lies_within_stack_range() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let local = 0u64;
+            let sp = ptr::addr_of!(local) as usize;     // 実行中の actor のスタック上のアドレス
+            let me = unsafe { CONTEXTS.front().unwrap().thread_id };
+            let (low, high) = stack_range(me).unwrap();
+            assert!(low <= sp && sp < high, "{:#x} is outside {:#x}..{:#x}", sp, low, high);
+        }, STACK);
+    }
+
+    /*  process ごと落ちる (abort, SIGSEGV) 動作を調べる test 用: 同じ test binary を子 process として name の test だけ実行する
+        子 process では GREEN_CHILD が設定され、test 本体はそれを見て落ちる側の処理を行う
+    */
+    fn run_child(name: &str) -> process::Output {
+        process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", &format!("green::tests::{}", name), "--nocapture", "--test-threads=1"])
+            .env("GREEN_CHILD", "1")
+            .output()
+            .unwrap()
+    }
+
+    fn in_child() -> bool {
+        std::env::var_os("GREEN_CHILD").is_some()
+    }
+
+    #[test]
+    fn entry_point_without_a_runtime_aborts() {
+        use std::os::unix::process::ExitStatusExt;
+        if in_child() {
+            entry_point();      // CTX_MAIN のないまま actor の入口に入った状態
+            return;
+        }
+        let out = run_child("entry_point_without_a_runtime_aborts");
+        assert_eq!(out.status.signal(), Some(6));       // SIGABRT: unwind せずに abort する
+        assert!(String::from_utf8_lossy(&out.stderr).contains("without an active runtime"));
+    }
+
+    #[test]
+    fn stacks_honour_a_64_kib_alignment() {
+        let _s = serial();
+        const ALIGN: usize = 64 * 1024;
+        set_config(RuntimeConfig { stack_align: ALIGN, ..RuntimeConfig::new() });
+        spawn_from_main(|| unsafe {
+            let id = spawn(wait_one, STACK);
+            for id in [CONTEXTS.front().unwrap().thread_id, id] {
+                let ctx = find_context(id).unwrap();
+                assert!((ctx.stack as usize).is_multiple_of(ALIGN), "stack at {:p} is not 64 KiB aligned", ctx.stack);
+                let (low, high) = stack_range(id).unwrap();
+                assert!(low < ctx.regs.sp as usize && ctx.regs.sp as usize <= high);
+            }
+            send(id, 0);
+        }, STACK);
+    }
+
+    static EXITS: Mutex<Vec<ExitReason>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn joiners_see_how_an_actor_exited() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let clean = spawn_fn(|| 7, STACK);
+            let panicking = spawn(|| panic!("boom"), STACK);
+            let killed = spawn(|| loop { yield_now() }, STACK);
+            let mut exits = vec![join(clean), join(panicking)];
+            kill(killed);
+            exits.push(join(killed));
+            *EXITS.lock().unwrap() = exits;     // actor 内の assert の失敗は catch_unwind に捕捉されるため、外で検査する
+        }, STACK);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(7), ExitReason::Panicked, ExitReason::Killed]);
+    }
+
+    static PENDING_FREES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    fn note_pending_frees() {
+        PENDING_FREES.lock().unwrap().push(unsafe {UNUSED_STACK.len()});
+    }
+
+    #[test]
+    fn stacks_of_back_to_back_exits_are_all_freed() {
+        let _s = serial();
+        PENDING_FREES.lock().unwrap().clear();
+        spawn_from_main(|| {
+            // 新しく始まる thread は rm_unused_stack() を通らないので、3 つが続けて終了すると解放待ちが溜まる
+            for _ in 0..3 {
+                let id = spawn_suspended(note_pending_frees, STACK);
+                resume(id);
+            }
+            yield_now();
+            note_pending_frees();       // 戻ってきた時には解放済み
+        }, STACK);
+        assert_eq!(*PENDING_FREES.lock().unwrap(), [0, 1, 2, 0]);
+    }
+
+    static DELIVERED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    // 1, 2, 3 を送り、hook を通して受信できた message を (0 を終端として) 返す
+    fn deliver_through_hook() -> Vec<u64> {
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let me = unsafe {CONTEXTS.front().unwrap().thread_id};
+            for msg in [1, 2, 3, 0] {
+                send(me, msg);
+            }
+            *DELIVERED.lock().unwrap() = std::iter::from_fn(|| receive().filter(|msg| *msg != 0)).collect();
+        }, STACK);
+        std::mem::take(&mut *DELIVERED.lock().unwrap())
+    }
+
+    #[test]
+    fn send_hooks_transform_and_drop_messages() {
+        let _s = serial();
+        set_send_hook(Box::new(|_, msg| Some(msg * 2)));
+        assert_eq!(deliver_through_hook(), vec![2, 4, 6]);
+        set_send_hook(Box::new(|_, msg| (msg <= 2).then_some(msg)));       // 2 より大きい message は捨てる
+        assert_eq!(deliver_through_hook(), vec![1, 2]);
+    }
+
+    // 実行された順序の記録 (actor ごとの印)
+    static LOG: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    fn log(mark: u64) {
+        LOG.lock().unwrap().push(mark);
+    }
+
+    fn take_log() -> Vec<u64> {
+        std::mem::take(&mut *LOG.lock().unwrap())
+    }
+
+    #[test]
+    fn demoting_itself_lets_a_lower_priority_peer_run_first() {
+        let _s = serial();
+        take_log();
+        spawn_from_main(|| {
+            let high = spawn_fn(|| {
+                set_priority(MAX_PRIORITY);
+                for _ in 0..3 {
+                    log(1);
+                    yield_now();        // 最も優先度が高いので自分に戻る
+                }
+                set_priority(0);
+                yield_now();
+                log(1);
+                0
+            }, STACK);
+            let peer = spawn_fn(|| {
+                log(2);
+                0
+            }, STACK);
+            join(high);
+            join(peer);
+        }, STACK);
+        assert_eq!(take_log(), vec![1, 1, 1, 2, 1]);
+    }
+
+    static GUARD_RESULT: Mutex<Option<(Result<u64, ActorError>, usize)>> = Mutex::new(None);
+
+    #[test]
+    fn failed_guard_setup_is_reported_by_try_spawn() {
+        let _s = serial();
+        set_platform(mock_platform());
+        spawn_from_main(|| {
+            fn noop() {}
+            FAIL_PROTECT.store(true, Ordering::SeqCst);     // root actor の生成後から失敗させる
+            let result = try_spawn(noop, STACK);
+            FAIL_PROTECT.store(false, Ordering::SeqCst);
+            *GUARD_RESULT.lock().unwrap() = Some((result, live_actors().len()));
+        }, STACK);
+        assert_eq!(GUARD_RESULT.lock().unwrap().take(), Some((Err(ActorError::GuardSetupFailed(Errno::EACCES)), 1)));   // 予約した id も返却済み
+        assert_eq!(mock_live_stacks(), 0);     // 失敗したスタックも root のスタックも返却済み
+    }
+
+    #[test]
+    fn failed_unprotect_leaks_the_stack_instead_of_panicking() {
+        let _s = serial();
+        set_platform(mock_platform());
+        FAIL_UNPROTECT.store(true, Ordering::SeqCst);
+        let leaked = leaked_stack_bytes();
+        spawn_from_main(|| {
+            let id = spawn_fn(|| 5, STACK);
+            *EXITS.lock().unwrap() = vec![join(id)];
+        }, STACK);
+        FAIL_UNPROTECT.store(false, Ordering::SeqCst);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(5)]);
+        assert_eq!(mock_live_stacks(), 2);       // dealloc_stack まで進まない
+        assert_eq!(leaked_stack_bytes(), leaked + 2 * STACK);     // root と子のスタック
+    }
+
+    static POLLED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+    static POLLER: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn poll_messages_sees_a_message_after_send_and_yield() {
+        let _s = serial();
+        spawn_from_main(|| {
+            POLLER.store(current_id() as usize, Ordering::SeqCst);
+            let sender = spawn_suspended(|| { send(POLLER.load(Ordering::SeqCst) as u64, 1); }, STACK);
+            resume(sender);
+            let mut polled = vec![poll_messages()];
+            yield_now();        // sender が send する
+            polled.push(poll_messages());
+            polled.push(receive() == Some(1));
+            *POLLED.lock().unwrap() = polled;
+        }, STACK);
+        assert_eq!(*POLLED.lock().unwrap(), [false, true, true]);
+    }
+
+    static STATS: Mutex<Option<(ExitReason, (u64, u64))>> = Mutex::new(None);
+
+    #[test]
+    fn message_stats_counts_sends_and_receives() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let me = current_id();
+            let worker = spawn_fn(move || {
+                for i in 0..5 {
+                    send(me, i);
+                }
+                for _ in 0..3 {
+                    receive();
+                }
+                let (sent, received) = message_stats(current_id());
+                sent * 10 + received
+            }, STACK);
+            for _ in 0..5 {
+                receive();
+            }
+            for i in 0..3 {
+                send(worker, i);
+            }
+            *STATS.lock().unwrap() = Some((join(worker), message_stats(me)));
+        }, STACK);
+        assert_eq!(STATS.lock().unwrap().take(), Some((ExitReason::Normal(53), (3, 5))));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "misaligned top of stack")]
+    fn a_misaligned_stack_size_is_caught() {
+        let _s = serial();
+        let _ = Context::new(from_entry(|| {}), STACK + 8, 1);      // 最上位が 16 の倍数にならない
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "AAPCS64 requires sp to be 16-byte aligned")]
+    fn a_misaligned_sp_is_caught() {
+        let _ = Registers::new(0x1008, 0);
+    }
+
+    static FAILING: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn a_panic_takes_down_linked_actors_unless_they_trap_exits() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let peer = spawn(wait_one, STACK);
+            let trapper = spawn_fn(|| {
+                trap_exit(true);
+                receive().unwrap()      // リンク先の終了は id の message として届く
+            }, STACK);
+            let failing = spawn_fn(|| {
+                yield_now();        // 先に link されるのを待つ
+                panic!("boom");
+            }, STACK);
+            link(failing, peer);
+            link(failing, trapper);
+            FAILING.store(failing as usize, Ordering::SeqCst);
+            *EXITS.lock().unwrap() = vec![join(failing), join(peer), join(trapper)];
+        }, STACK);
+        let failing = FAILING.load(Ordering::SeqCst) as u64;
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Panicked, ExitReason::Killed, ExitReason::Normal(failing)]);
+    }
+
+    #[test]
+    fn a_one_page_stack_is_rejected() {
+        let _s = serial();
+        spawn_from_main(|| {
+            fn noop() {}
+            *GUARD_RESULT.lock().unwrap() = Some((try_spawn(noop, PAGE_SIZE), live_actors().len()));     // ガードページだけで使用可能領域がない
+        }, STACK);
+        let (result, live) = GUARD_RESULT.lock().unwrap().take().unwrap();
+        let err = result.unwrap_err();
+        assert_eq!(err, ActorError::StackTooSmall { stack_size: PAGE_SIZE, guard_size: PAGE_SIZE });
+        assert!(err.to_string().contains("stack"));
+        assert_eq!(live, 1);
+    }
+
+    #[test]
+    fn ids_allocated_from_several_threads_are_unique() {
+        let _s = serial();
+        *ID.lock().unwrap() = Some(IdSet::default());
+        let threads: Vec<_> = (0..4).map(|_| thread::spawn(|| (0..1000).map(|_| get_id()).collect::<Vec<u64>>())).collect();
+        let ids: HashSet<u64> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        assert_eq!(ids.len(), 4000);
+        assert_eq!(ID.lock().unwrap().take().unwrap().len(), 4000);
+    }
+
+    static DUMP: Mutex<Option<(String, [u64; 3])>> = Mutex::new(None);
+
+    #[test]
+    fn dump_state_lists_runnable_waiting_and_queued() {
+        let _s = serial();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        assert_eq!(dump_state(), "runtime is not running\n");
+        spawn_from_main(|| {
+            let waiter = spawn(wait_one, STACK);        // 受信待ちになって戻ってくる
+            let runnable = spawn_suspended(wait_one, STACK);
+            resume(runnable);       // 実行可能だがまだ動いていない
+            let suspended = spawn_suspended(wait_one, STACK);
+            send(suspended, 1);
+            send(suspended, 2);
+            *DUMP.lock().unwrap() = Some((dump_state(), [waiter, runnable, suspended]));
+            for id in [waiter, runnable] {
+                send(id, 0);
+            }
+            resume(suspended);
+            for id in [waiter, runnable, suspended] {
+                join(id);
+            }
+        }, STACK);
+        let (dump, [waiter, runnable, suspended]) = DUMP.lock().unwrap().take().unwrap();
+        assert!(dump.contains("runnable: 2\n"), "{}", dump);
+        assert!(dump.contains(&format!("] {} (priority", runnable)), "{}", dump);
+        assert!(dump.contains(&format!("waiting: 1\n    {}\n", waiter)), "{}", dump);
+        assert!(dump.contains(&format!("suspended: 1\n    {}\n", suspended)), "{}", dump);
+        assert!(dump.contains(&format!("mailboxes: 1 (2 message(s) queued)\n    {}: 2\n", suspended)), "{}", dump);
+    }
+
+    /*  ping-pong: ponger は受け取った値に 1 を足して pinger に返し、pinger は ROUNDS 往復後に最後の値を残す
+        global_runtime (free function) と explicit_runtime (Runtime のメソッド) で同じ結果になることを確認する
+    */
+    static PONGED: Mutex<Option<u64>> = Mutex::new(None);
+
+    #[cfg(feature = "global_runtime")]
+    #[test]
+    fn ping_pong_with_free_functions() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let pinger = current_id();
+            let ponger = spawn_fn(move || {
+                for _ in 0..ROUNDS {
+                    let n = receive().unwrap();
+                    send(pinger, n + 1);
+                }
+                0
+            }, STACK);
+            let mut n = 0;
+            for _ in 0..ROUNDS {
+                send(ponger, n);
+                n = receive().unwrap();
+            }
+            join(ponger);
+            *PONGED.lock().unwrap() = Some(n);
+        }, STACK);
+        assert_eq!(PONGED.lock().unwrap().take(), Some(ROUNDS));
+    }
+
+    #[cfg(feature = "explicit_runtime")]
+    #[test]
+    fn ping_pong_with_runtime_handle() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let rt = Runtime::current().unwrap();
+            let pinger = rt.current_id();
+            let ponger = rt.spawn_fn(move || {
+                let rt = Runtime::current().unwrap();
+                for _ in 0..ROUNDS {
+                    let n = rt.receive().unwrap();
+                    rt.send(pinger, n + 1);
+                }
+                0
+            }, STACK);
+            let mut n = 0;
+            for _ in 0..ROUNDS {
+                rt.send(ponger, n);
+                n = rt.receive().unwrap();
+            }
+            rt.join(ponger);
+            *PONGED.lock().unwrap() = Some(n);
+        }, STACK);
+        assert_eq!(PONGED.lock().unwrap().take(), Some(ROUNDS));
+    }
+
+    #[test]
+    fn the_panic_handler_receives_the_panicking_actor() {
+        let _s = serial();
+        static PANICKED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+        PANICKED.lock().unwrap().clear();
+        set_panic_handler(Box::new(|id, _| PANICKED.lock().unwrap().push(id)));
+        static SPAWNED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+        spawn_from_main(|| {
+            let id = spawn(|| panic!("boom"), STACK);
+            join(id);
+            *SPAWNED.lock().unwrap() = vec![id];
+        }, STACK);
+        assert_eq!(*PANICKED.lock().unwrap(), *SPAWNED.lock().unwrap());
+    }
+
+    #[test]
+    fn run_loop_counts_until_a_sentinel() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let counter = spawn_fn(|| {
+                let mut count = 0;
+                run_loop(|msg| {
+                    if msg == 0 {
+                        return ControlFlow::Break(());      // 終端
+                    }
+                    count += 1;
+                    ControlFlow::Continue(())
+                });
+                count
+            }, STACK);
+            for msg in [5, 6, 7, 0, 8] {
+                send(counter, msg);
+            }
+            *EXITS.lock().unwrap() = vec![join(counter)];
+        }, STACK);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(3)]);
+    }
+
+    #[test]
+    fn shutdown_wakes_a_cancellable_receive_with_none() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let token = CancelToken::new();
+            let waiter = spawn_fn(move || receive_cancellable(token).map_or(1, |_| 2), STACK);
+            yield_now();
+            shutdown();
+            *EXITS.lock().unwrap() = vec![join(waiter)];
+        }, STACK);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(1)]);
+    }
+
+    static SPAWNED_MANY: Mutex<Option<(Vec<u64>, bool)>> = Mutex::new(None);
+    static LIVE_AT_START: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    // 起動時に見えた生存 actor 数を記録してから受信待ち
+    fn count_live_then_wait() {
+        LIVE_AT_START.lock().unwrap().push(live_actors().len());
+        receive();
+    }
+
+    #[test]
+    fn spawn_many_creates_unique_workers_in_one_round() {
+        let _s = serial();
+        LIVE_AT_START.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let ids = spawn_many(count_live_then_wait, STACK, 100);
+            let alive = ids.iter().all(|id| is_alive(*id));
+            for &id in &ids {
+                send(id, 0);
+                join(id);
+            }
+            *SPAWNED_MANY.lock().unwrap() = Some((ids, alive));
+        }, STACK);
+        let (ids, alive) = SPAWNED_MANY.lock().unwrap().take().unwrap();
+        // 生成中は yield しない -> どの worker も最初に動く時点で全員 (と生成した actor) が揃っている
+        assert_eq!(*LIVE_AT_START.lock().unwrap(), vec![101; 100]);
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 100);
+        assert!(alive);
+    }
+
+    // yield し続けて終了しない actor
+    fn spin() {
+        loop {
+            yield_now();
+        }
+    }
+
+    static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn an_endless_actor_is_stopped_at_the_budget() {
+        let _s = serial();
+        spawn_from_main_with_budget(spin, STACK, 10);       // 戻ってくれば打ち切られている
+        assert!(live_actors().is_empty());      // 終了しなかった actor も破棄済み
+        SESSIONS.store(0, Ordering::SeqCst);
+        spawn_from_main(|| {
+            SESSIONS.fetch_add(1, Ordering::SeqCst);
+        }, STACK);
+        assert_eq!(SESSIONS.load(Ordering::SeqCst), 1);      // 次の session を始められる
+    }
+
+    static ACKS: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn receiving_a_tracked_message_acks_it() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let receiver = spawn_fn(|| receive().unwrap(), STACK);
+            let ack_id = send_tracked(receiver, 7);
+            *ACKS.lock().unwrap() = vec![wait_ack(ack_id), is_acked(ack_id)];
+            *EXITS.lock().unwrap() = vec![join(receiver)];
+        }, STACK);
+        assert_eq!(*ACKS.lock().unwrap(), [true, true]);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(7)]);
+    }
+
+    #[test]
+    fn wait_ack_timeout_gives_up_without_an_ack() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let receiver = spawn_fn(|| {
+                for _ in 0..100 {
+                    yield_now();
+                }
+                receive().unwrap()
+            }, STACK);
+            let ack_id = send_tracked(receiver, 7);
+            let timed_out = !wait_ack_timeout(ack_id, 10);
+            *ACKS.lock().unwrap() = vec![timed_out, wait_ack(ack_id)];      // 遅れて受信されれば ack される
+            join(receiver);
+        }, STACK);
+        assert_eq!(*ACKS.lock().unwrap(), [true, true]);
+    }
+
+    #[test]
+    fn an_untracked_message_with_the_same_value_does_not_ack() {
+        let _s = serial();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let receiver = spawn_fn(|| {
+                receive();
+                yield_now();
+                receive();
+                0
+            }, STACK);
+            send(receiver, 7);
+            let ack_id = send_tracked(receiver, 7);
+            yield_now();        // receiver は追跡していない方の 7 だけを受信する
+            let early = is_acked(ack_id);
+            *ACKS.lock().unwrap() = vec![early, wait_ack(ack_id)];
+            join(receiver);
+        }, STACK);
+        assert_eq!(*ACKS.lock().unwrap(), [false, true]);
+    }
+
+    #[test]
+    fn wait_ack_fails_when_the_recipient_exits() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let receiver = spawn_fn(|| {
+                yield_now();
+                0       // 受信せずに終了
+            }, STACK);
+            let ack_id = send_tracked(receiver, 7);
+            *ACKS.lock().unwrap() = vec![wait_ack(ack_id), is_acked(ack_id)];
+            join(receiver);
+        }, STACK);
+        assert_eq!(*ACKS.lock().unwrap(), [false, false]);
+    }
+
+    #[test]
+    fn wait_ack_fails_when_the_send_hook_drops_the_message() {
+        let _s = serial();
+        set_send_hook(Box::new(|_, _| None));
+        spawn_from_main(|| {
+            let receiver = spawn(wait_one, STACK);
+            let ack_id = send_tracked(receiver, 7);
+            *ACKS.lock().unwrap() = vec![wait_ack(ack_id), is_acked(ack_id)];
+            clear_send_hook();
+            send(receiver, 0);
+            join(receiver);
+        }, STACK);
+        assert_eq!(*ACKS.lock().unwrap(), [false, false]);
+    }
+
+    #[test]
+    fn the_runtime_runs_on_a_platform_without_syscalls() {
+        let _s = serial();
+        set_platform(mock_platform());
+        NO_SYSCALLS.store(true, Ordering::SeqCst);
+        spawn_from_main(|| {
+            let me = current_id();
+            let ponger = spawn_fn(move || {
+                let n = receive().unwrap();
+                send(me, n + 1);
+                0
+            }, STACK);
+            send(ponger, 1);
+            *PONGED.lock().unwrap() = receive();
+            join(ponger);
+        }, STACK);
+        assert_eq!(PONGED.lock().unwrap().take(), Some(2));
+        assert_eq!(STACK_ALLOCS.load(Ordering::SeqCst), 2);     // root と ponger のスタック
+        assert_eq!(STACK_FREES.load(Ordering::SeqCst), 2);
+        assert_eq!(PROTECTS.load(Ordering::SeqCst), 2);
+        assert_eq!(UNPROTECTS.load(Ordering::SeqCst), 2);
+    }
+
+    static BUDGET: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn the_stack_budget_rejects_spawns_until_a_stack_is_freed() {
+        let _s = serial();
+        let leaked = unsafe {STACK_TOTAL};      // should_panic のテストが解放せずに残したスタック
+        set_config(RuntimeConfig { total_stack_budget: leaked + 3 * STACK, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let first = spawn(wait_one, STACK);
+            let second = spawn(wait_one, STACK);        // root と合わせて予算を使い切る
+            let rejected = matches!(try_spawn(wait_one, STACK), Err(ActorError::StackBudgetExceeded { .. }));
+            send(first, 0);
+            join(first);        // first のスタックが解放される
+            let third = try_spawn(wait_one, STACK);
+            *BUDGET.lock().unwrap() = vec![rejected, third.is_ok()];
+            for id in [Some(second), third.ok()].into_iter().flatten() {
+                send(id, 0);
+                join(id);
+            }
+        }, STACK);
+        assert_eq!(*BUDGET.lock().unwrap(), [true, true]);      // 予算超過で拒否され、解放後は成功する
+    }
+
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+    static DIRECT: AtomicBool = AtomicBool::new(false);
+    static SWITCHES: Mutex<Option<usize>> = Mutex::new(None);
+
+    // spin と同じだが、実行された回数を SPINS に数える
+    fn counting_spin() {
+        loop {
+            SPINS.fetch_add(1, Ordering::SeqCst);
+            yield_now();
+        }
+    }
+
+    fn reply_to(to: u64, msg: u64) {
+        if DIRECT.load(Ordering::SeqCst) {
+            send_and_switch_to(to, msg);
+        } else {
+            send(to, msg);
+        }
+    }
+
+    // ROUNDS 往復の ping-pong の間に、無関係な actor (counting_spin) に切り替わった回数
+    fn unrelated_switches(direct: bool) -> usize {
+        DIRECT.store(direct, Ordering::SeqCst);
+        spawn_from_main(|| {
+            let me = current_id();
+            let ponger = spawn_fn(move || {
+                for _ in 0..ROUNDS {
+                    let n = receive().unwrap();
+                    reply_to(me, n + 1);
+                }
+                0
+            }, STACK);
+            let spinner = spawn(counting_spin, STACK);
+            let before = SPINS.load(Ordering::SeqCst);
+            for i in 0..ROUNDS {
+                reply_to(ponger, i);
+                assert_eq!(receive(), Some(i + 1));
+            }
+            *SWITCHES.lock().unwrap() = Some(SPINS.load(Ordering::SeqCst) - before);
+            kill(spinner);
+            join(ponger);
+        }, STACK);
+        SWITCHES.lock().unwrap().take().unwrap()
+    }
+
+    #[test]
+    fn send_and_switch_to_skips_unrelated_actors() {
+        let _s = serial();
+        let plain = unrelated_switches(false);
+        let direct = unrelated_switches(true);
+        assert!(plain >= ROUNDS as usize, "plain send: {}", plain);       // 往復ごとに spin を経由する
+        assert!(direct < plain / 2, "send_and_switch_to: {} vs plain send: {}", direct, plain);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn set_next_forces_the_next_actor() {
+        let _s = serial();
+        take_log();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let ids: Vec<u64> = (1..=3).map(|mark| spawn_fn(move || {
+                receive();
+                log(mark);
+                0
+            }, STACK)).collect();
+            for &id in &ids {
+                send(id, 0);        // 3 つとも起床させてから yield する
+            }
+            assert!(!set_next(current_id() ^ 1));       // 実行可能でない id
+            assert!(set_next(ids[2]));
+            yield_now();
+            for id in ids {
+                join(id);
+            }
+        }, STACK);
+        assert_eq!(take_log(), vec![3, 1, 2]);      // 指定した actor の後は queue の順
+    }
+
+    // 終わらない再帰でスタックを使い切る (最適化で loop にならないよう、フレームごとに配列を置く)
+    fn overflow(depth: u64) -> u64 {
+        let frame = [depth; 64];
+        std::hint::black_box(&frame);
+        if depth == u64::MAX {
+            return 0;
+        }
+        overflow(depth + 1) + frame[0]
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_stack_overflow_only_ends_the_overflowing_actor() {
+        if in_child() {
+            let _s = serial();
+            spawn_from_main(|| {
+                let sibling = spawn(wait_one, STACK);
+                let overflowing = spawn_fn(|| overflow(0), STACK);
+                let reason = join(overflowing);
+                let alive = is_alive(sibling);
+                send(sibling, 0);
+                if reason == ExitReason::StackOverflow && alive && join(sibling) == ExitReason::Normal(0) {
+                    println!("sibling survived");
+                }
+            }, STACK);
+            return;
+        }
+        let out = run_child("a_stack_overflow_only_ends_the_overflowing_actor");      // 回収に失敗すると子 process ごと落ちる
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert!(String::from_utf8_lossy(&out.stdout).contains("sibling survived"));
+    }
+
+    #[test]
+    fn follow_ups_posted_to_self_arrive_in_order() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let worker = spawn_fn(|| {
+                let first = receive().unwrap();
+                for step in 1..=3 {
+                    post_self(first + step);        // 処理中に後続の処理を予約する
+                }
+                (0..3).fold(0, |acc, _| acc * 10 + receive().unwrap())
+            }, STACK);
+            send(worker, 0);
+            *EXITS.lock().unwrap() = vec![join(worker)];
+        }, STACK);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(123)]);
+    }
+
+    fn log_priority() {
+        log(priority() as u64);
+    }
+
+    static BUILT: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn actor_builder_applies_every_option() {
+        let _s = serial();
+        take_log();
+        spawn_from_main(|| {
+            let id = ActorBuilder::new()
+                .stack(2 * STACK)
+                .priority(5)
+                .trap_exit(true)
+                .suspended()
+                .spawn(log_priority);
+            let (low, high) = stack_range(id).unwrap();
+            let trapping = unsafe {find_context(id)}.unwrap().trap_exit;
+            *BUILT.lock().unwrap() = vec![take_log().len(), is_alive(id) as usize, high - low, trapping as usize];
+            assert!(resume(id));
+            join(id);
+        }, STACK);
+        assert_eq!(*BUILT.lock().unwrap(), [0, 1, 2 * STACK - PAGE_SIZE, 1]);      // suspended のまま生存; ガードページを除く
+        assert_eq!(take_log(), vec![5]);
+    }
+
+    fn log_ones() {
+        for _ in 0..6 {
+            log(1);
+            yield_now();
+        }
+    }
+
+    fn log_twos() {
+        for _ in 0..2 {
+            log(2);
+            yield_now();
+        }
+    }
+
+    #[test]
+    fn actor_builder_applies_weight_mailbox_cap_and_supervision() {
+        let _s = serial();
+        take_log();
+        set_config(RuntimeConfig { spawn_yields: false, ..RuntimeConfig::new() });      // heavy と light が揃ってから動かす
+        with_runtime(|| {
+            let capped = ActorBuilder::new().stack(STACK).mailbox_cap(1).suspended().spawn(wait_one);
+            send(capped, 1);
+            send(capped, 2);        // 上限に達しているので捨てられる
+            assert_eq!(mailbox_len(capped), 1);
+            assert!(resume(capped));
+            join(capped);
+
+            let heavy = ActorBuilder::new().stack(STACK).weight(3).spawn(log_ones);
+            let light = spawn(log_twos, STACK);
+            join(heavy);
+            join(light);
+            assert_eq!(take_log(), [1, 1, 1, 2, 1, 1, 1, 2]);       // heavy は 3 slice ずつ続けて実行される
+
+            let child = ActorBuilder::new().stack(STACK).supervised(SupervisionPolicy::TrapExit).spawn(panics);
+            assert_eq!(receive(), Some(child));     // link 済みで trap_exit 中: 子の異常終了が message で届く
+        });
+    }
+
+    #[test]
+    fn a_three_actor_join_cycle_releases_exactly_one() {
+        let _s = serial();
+        set_config(RuntimeConfig { break_wait_cycles: true, send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let ids: Vec<u64> = (0..3).map(|_| spawn_fn(|| {
+                let target = receive().unwrap();
+                match try_join(target) {
+                    Err(ActorError::WaitCycleBroken) => 1,      // 循環を断ち切るために解放された
+                    _ => 0,
+                }
+            }, STACK)).collect();
+            for i in 0..3 {
+                send(ids[i], ids[(i + 1) % 3]);     // a -> b -> c -> a の順に join する
+            }
+            *EXITS.lock().unwrap() = ids.iter().map(|id| join(*id)).collect();
+        }, STACK);
+        let exits = EXITS.lock().unwrap().clone();
+        assert_eq!(exits.iter().filter(|r| **r == ExitReason::Normal(1)).count(), 1, "{:?}", exits);
+        assert_eq!(exits.iter().filter(|r| **r == ExitReason::Normal(0)).count(), 2, "{:?}", exits);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn guard_faults_count_each_recovered_overflow() {
+        if in_child() {
+            let _s = serial();
+            spawn_from_main(|| {
+                let ids: Vec<u64> = (0..3).map(|_| spawn_fn(|| overflow(0), STACK)).collect();
+                let overflowed = ids.iter().all(|id| join(*id) == ExitReason::StackOverflow);
+                let total: u64 = ids.iter().map(|id| guard_faults(*id)).sum();
+                if overflowed && ids.iter().all(|id| guard_faults(*id) == 1) {     // スタックは伸長しないため actor ごとに 1 回
+                    println!("guard faults: {}", total);
+                }
+            }, STACK);
+            return;
+        }
+        let out = run_child("guard_faults_count_each_recovered_overflow");
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert!(String::from_utf8_lossy(&out.stdout).contains("guard faults: 3"));
+    }
+
+    // runtime より前に登録されていた SIGSEGV handler: 呼ばれたら終了コード 42 で抜ける
+    extern "C" fn prior_segv(_sig: libc::c_int, _info: *mut libc::siginfo_t, _uctx: *mut libc::c_void) {
+        unsafe {libc::_exit(42)};
+    }
+
+    // prior_segv を登録し、それまでの設定を返す
+    unsafe fn install_prior_segv() -> libc::sigaction {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = prior_segv as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut orig: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGSEGV, &action, &mut orig);
+        orig
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn the_previous_segv_handler_is_restored_after_teardown() {
+        let _s = serial();
+        unsafe {
+            let orig = install_prior_segv();
+            spawn_from_main(|| {}, STACK);
+            let mut now: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGSEGV, ptr::null(), &mut now);
+            libc::sigaction(libc::SIGSEGV, &orig, ptr::null_mut());
+            assert_eq!(now.sa_sigaction, prior_segv as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) as usize);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_fault_outside_the_guard_page_reaches_the_previous_handler() {
+        if in_child() {
+            let _s = serial();
+            unsafe {install_prior_segv()};
+            spawn_from_main(|| {
+                unsafe {ptr::read_volatile(8 as *const u8)};       // どの actor のガードページでもない
+            }, STACK);
+            return;
+        }
+        let out = run_child("a_fault_outside_the_guard_page_reaches_the_previous_handler");
+        assert_eq!(out.status.code(), Some(42), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+
+    static SNAPSHOT: Mutex<Option<(RuntimeSnapshot, u64, u64)>> = Mutex::new(None);
+
+    #[test]
+    fn snapshot_lists_runnable_actors_and_their_mailbox_depth() {
+        let _s = serial();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let a = spawn(spin, STACK);
+            let b = spawn(spin, STACK);     // spin は yield し続けるので実行可能なまま
+            send(a, 1);
+            send(a, 2);
+            send(b, 3);
+            *SNAPSHOT.lock().unwrap() = Some((snapshot(), a, b));
+            kill(a);
+            kill(b);
+        }, STACK);
+        let (snap, a, b) = SNAPSHOT.lock().unwrap().take().unwrap();
+        let ids: Vec<u64> = snap.runnable.iter().map(|actor| actor.id).collect();
+        assert_eq!(&ids[1..], &[a, b]);        // 先頭は実行中の root
+        assert_eq!(snap.runnable[1].mailbox_depth, 2);
+        assert_eq!(snap.runnable[2].mailbox_depth, 1);
+        assert_eq!(snap.runnable[1].stack_size, STACK);
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(&snap).unwrap();
+            assert_eq!(json["runnable"][1]["id"], a);
+            assert_eq!(json["runnable"][1]["mailbox_depth"], 2);
+            assert_eq!(json["runnable"][2]["id"], b);
+            assert_eq!(json["runnable"][2]["mailbox_depth"], 1);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "receive: no actor is running")]
+    fn receive_before_spawn_from_main_is_a_clear_error() {
+        let _s = serial();
+        receive();      // unwrap on None ではなく、actor の外であることを示す panic
+    }
+
+    #[test]
+    #[should_panic(expected = "yield_now: ")]
+    fn yield_now_outside_the_runtime_names_itself() {
+        let _s = serial();
+        yield_now();
+    }
+
+    static SLOW_WORKER: AtomicUsize = AtomicUsize::new(0);
+    static COUNTS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    // 1 job ごとに yield する worker; SLOW_WORKER だけは 1 job に 10 倍の時間をかける
+    fn uneven_worker() {
+        while pool_receive().is_some() {
+            log(current_id());
+            let turns = if current_id() as usize == SLOW_WORKER.load(Ordering::SeqCst) { 10 } else { 1 };
+            for _ in 0..turns {
+                yield_now();
+            }
+        }
+    }
+
+    #[test]
+    fn a_pool_spreads_jobs_around_a_slow_worker() {
+        let _s = serial();
+        take_log();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let pool = spawn_pool(uneven_worker, STACK, 4);
+            let workers = pool_workers(pool);
+            SLOW_WORKER.store(workers[0] as usize, Ordering::SeqCst);
+            for job in 0..ROUNDS {
+                pool_submit(pool, job);     // round-robin で各 worker に 25 個ずつ積まれる
+            }
+            while (LOG.lock().unwrap().len() as u64) < ROUNDS {
+                yield_now();
+            }
+            let done = take_log();
+            *COUNTS.lock().unwrap() = workers.iter().map(|w| done.iter().filter(|id| *id == w).count()).collect();
+            shutdown();     // pool_receive が None を返して worker が終了する
+        }, STACK);
+        let counts = COUNTS.lock().unwrap().clone();
+        assert!(counts.iter().all(|n| *n > 0), "{:?}", counts);
+        assert!(counts[1..].iter().all(|n| *n > counts[0]), "{:?}", counts);        // 遅い worker の backlog は他の worker が奪う
+    }
+
+    // session 中に使われる global 変数がすべて初期状態に戻っているか (teardown に追加し忘れた global を検出する)
+    unsafe fn assert_globals_reset() {
+        assert!(CTX_MAIN.is_none());
+        assert!(CONTEXTS.is_empty() && CONTEXT_POOL.is_empty() && UNUSED_STACK.is_empty());
+        assert!(ID.lock().unwrap().is_none());
+        assert!(MESSAGES.is_null() && WAITING.is_null() && SUSPENDED.is_null());
+        assert!(BLOCKING_RESULTS.is_null() && EXITED.is_null() && JOINERS.is_null() && LINKS.is_null());
+        assert!(TOKEN_WAITERS.is_null() && CANCELLED.is_null());
+        assert!(UNACKED.is_null() && TRACKED.is_null() && LOST_ACKS.is_null());
+        assert!(POOLS.is_null() && POOL_OF.is_null() && GROUPS.is_null() && LOCKED_STACKS.is_null() && DEDUP_SEEN.is_null());
+        assert!(WAITING_ON.is_null() && WAIT_REASONS.is_null() && BARRIERS.is_null() && BROKEN.is_null() && GUARD_FAULTS.is_null());
+        assert!(MAILBOX_IDS.is_null());
+        assert!(SENT_COUNT.is_null() && RECV_COUNT.is_null() && DROPPED_COUNT.is_null() && PENDING_REPLIES.is_null() && REPLIES.is_null());
+        #[cfg(debug_assertions)]
+        assert!(SPAWN_COUNTS.is_null() && WOKEN_BY.is_null());
+        #[cfg(feature = "testing")]
+        assert!(FORCED_NEXT.is_none());
+        assert_eq!((TICKS, TICK_LIMIT, SHUTTING_DOWN, IN_SCHEDULER), (0, None, false, false));
+        assert_eq!((NEXT_POOL, NEXT_TOKEN, NEXT_ACK, NEXT_CORR), (0, 0, 0, 0));
+        assert!(!RESCHEDULE.load(Ordering::SeqCst));
+        assert_eq!(BLOCKING_PENDING, 0);
+        assert!(BLOCKING_DONE.lock().unwrap().is_empty());
+        assert!(EXTERNAL.lock().unwrap().is_empty() && !EXTERNAL_PENDING.load(Ordering::SeqCst));
+        assert!(COMPLETIONS.lock().unwrap().is_empty());
+        assert_eq!(STACK_TOTAL, 0);
+    }
+
+    fn pool_worker() {
+        while pool_receive().is_some() {}
+    }
+
+    #[test]
+    fn teardown_resets_every_global() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let me = current_id();
+            let server = spawn_fn(|| {
+                let (corr, request) = receive_correlated();
+                reply_correlated(corr, request);
+                receive().unwrap()
+            }, STACK);
+            join_group("g", server);
+            link(me, server);
+            let ack_id = send_tracked(server, 1);
+            call_with_correlation(server, 2);
+            *ACKS.lock().unwrap() = vec![wait_ack(ack_id)];
+            run_blocking(|| 0);
+            let (id, handle) = spawn_async(|| 4, STACK);
+            join(id);
+            drop(handle);
+            let pool = spawn_pool(pool_worker, STACK, 2);
+            pool_submit(pool, 5);
+            let token = CancelToken::new();
+            cancel(token);
+            join(server);
+            request_reschedule();       // 満たされないまま teardown される
+            shutdown();
+        }, STACK);
+        assert_eq!(*ACKS.lock().unwrap(), [true]);
+        unsafe {
+            assert_globals_reset();
+        }
+        spawn_from_main_with_budget(spin, STACK, 3);     // tick 上限で打ち切り、残った actor を teardown で破棄する
+        unsafe {
+            assert_globals_reset();
+        }
+    }
+
+    /*  ガードページの境界を子 process で実際に書き込んで確かめる
+        使用可能領域の最下位 byte への書き込みは成功し、そのすぐ下 (ガードページの最上位 byte) への書き込みは SIGSEGV になる
+    */
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn guard_probe_faults_just_below_the_usable_stack() {
+        use std::os::unix::process::ExitStatusExt;
+        if in_child() {
+            let _s = serial();
+            spawn_from_main(|| {
+                let id = spawn(wait_one, STACK);
+                assert_guard_correct(id);
+                let (low, _) = stack_range(id).unwrap();
+                unsafe {
+                    ptr::write_volatile(low as *mut u8, 1);
+                    println!("PASS: usable stack is writable");
+                    ptr::write_volatile((low - 1) as *mut u8, 1);       // 実行中の actor のガードページではないため回収されない
+                }
+                println!("FAIL: write below the usable stack succeeded");
+            }, STACK);
+            return;
+        }
+        let out = run_child("guard_probe_faults_just_below_the_usable_stack");
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.contains("PASS"), "{}", String::from_utf8_lossy(&out.stderr));
+        assert!(!stdout.contains("FAIL"));
+        assert_eq!(out.status.signal(), Some(libc::SIGSEGV));
+    }
+
+    fn three_digits() {
+        log((0..3).fold(0, |acc, _| acc * 10 + receive().unwrap()));
+    }
+
+    #[test]
+    fn a_priority_mailbox_returns_high_medium_low() {
+        let _s = serial();
+        take_log();
+        spawn_from_main(|| {
+            let id = ActorBuilder::new().priority_mailbox().suspended().spawn(three_digits);
+            send_prio(id, 1, 0);        // low
+            send_prio(id, 3, 9);        // high
+            send_prio(id, 2, 5);        // medium
+            resume(id);
+            join(id);
+        }, STACK);
+        assert_eq!(take_log(), vec![321]);
+    }
+
+    static DETAIL: Mutex<Vec<Vec<(u64, WaitReason)>>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn waiting_detail_reports_why_each_actor_waits() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let receiver = spawn(wait_one, STACK);
+            let joiner = spawn_fn(move || { join(receiver); 0 }, STACK);
+            yield_now();        // 2 つとも待ち状態になる
+            let detail = waiting_detail();
+            send(receiver, 0);
+            for id in [receiver, joiner] {
+                join(id);
+            }
+            let mut expected = vec![(receiver, WaitReason::Message), (joiner, WaitReason::Join(receiver))];
+            expected.sort_by_key(|(id, _)| *id);        // waiting_detail は id の昇順
+            *DETAIL.lock().unwrap() = vec![detail, expected, waiting_detail()];
+        }, STACK);
+        let detail = DETAIL.lock().unwrap().clone();
+        assert_eq!(detail[0], detail[1]);
+        assert!(detail[2].is_empty());
+    }
+
+    // 一度 yield してから 2 つの message を受け取り、2 桁の数として記録する
+    fn yield_then_two_digits() {
+        yield_now();
+        log(receive().unwrap() * 10 + receive().unwrap());
+    }
+
+    #[test]
+    fn spawn_with_msg_is_received_first() {
+        let _s = serial();
+        take_log();
+        spawn_from_main(|| {
+            let id = spawn_with_msg(yield_then_two_digits, STACK, 4);
+            send(id, 7);        // 最初の receive より前に別の send が届く
+            join(id);
+        }, STACK);
+        assert_eq!(take_log(), vec![47]);        // init の 4 が先
+    }
+
+    // phase ごとに到着の順番がずれるよう、thread_id に応じた回数だけ yield してから barrier に着く
+    fn two_phases() {
+        for phase in 1..=2 {
+            for _ in 0..current_id() % 3 {
+                yield_now();
+            }
+            log(phase);
+            barrier(1, 4).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_barrier_holds_four_actors_between_phases() {
+        let _s = serial();
+        take_log();
+        spawn_from_main(|| {
+            let ids: Vec<u64> = (0..4).map(|_| spawn(two_phases, STACK)).collect();
+            *EXITS.lock().unwrap() = ids.into_iter().map(join).collect();
+        }, STACK);
+        assert_eq!(*EXITS.lock().unwrap(), vec![ExitReason::Normal(0); 4]);
+        assert_eq!(take_log(), vec![1, 1, 1, 1, 2, 2, 2, 2]);       // 全員が phase 1 を終えるまで phase 2 に進まない
+    }
+
+    static WOKEN: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn send_reports_whether_it_woke_the_receiver() {
+        let _s = serial();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let parked: Vec<u64> = (0..3).map(|_| spawn(wait_one, STACK)).collect();       // 受信待ちになる
+            let runnable: Vec<u64> = (0..2).map(|_| spawn(spin, STACK)).collect();      // 実行可能なまま
+            *WOKEN.lock().unwrap() = vec![
+                send(parked[0], 0),
+                send(runnable[0], 0),
+                broadcast(&[parked[1], parked[2], runnable[1]], 0),
+            ];
+            for id in runnable {
+                kill(id);
+            }
+            for id in parked {
+                join(id);
+            }
+        }, STACK);
+        assert_eq!(*WOKEN.lock().unwrap(), [1, 0, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "broadcast: ")]
+    fn broadcast_outside_the_runtime_names_itself() {
+        let _s = serial();
+        broadcast(&[], 0);
+    }
+
+    // 局所変数を spill する leaf 関数: スタックの最上位付近を使う
+    #[inline(never)]
+    fn leaf_with_spills() -> u64 {
+        let mut locals = [0u64; 32];
+        for (i, slot) in locals.iter_mut().enumerate() {
+            *slot = std::hint::black_box(i as u64);
+        }
+        std::hint::black_box(&locals).iter().sum()
+    }
+
+    fn call_leaf() {
+        std::hint::black_box(leaf_with_spills());
+    }
+
+    static RED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn the_top_of_stack_leaves_a_red_zone() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let id = spawn_suspended(call_leaf, STACK);     // 実行前の sp を見る
+            let (_, top) = stack_range(id).unwrap();
+            let gap = top - unsafe {find_context(id)}.unwrap().regs.sp as usize;
+            resume(id);
+            *RED.lock().unwrap() = vec![gap, (join(id) == ExitReason::Normal(0)) as usize];
+        }, STACK);
+        let red = RED.lock().unwrap().clone();
+        assert!(red[0] >= RED_ZONE, "{:?}", red);      // 最上位に余白を残して開始する
+        assert_eq!(red[1], 1);
+    }
+
+    static LONE: Mutex<Option<ExitReason>> = Mutex::new(None);
+    static EXTERNAL_MSG: Mutex<Option<u64>> = Mutex::new(None);
+
+    // root が join で待つ間に child だけが receive する (deadlock) ときの child の終了理由
+    // external: Some(msg) なら別の OS スレッドから少し遅れて send_external する
+    fn lone_receive(action: DeadlockAction, external: Option<u64>) -> ExitReason {
+        set_config(RuntimeConfig { on_deadlock: action, ..RuntimeConfig::new() });
+        *EXTERNAL_MSG.lock().unwrap() = external;
+        spawn_from_main(|| {
+            let id = spawn_fn(|| {
+                yield_now();        // root が join で待ってから、child が最後に待ち状態になる
+                receive().map_or(0, |msg| msg + 1)
+            }, STACK);
+            let sender = EXTERNAL_MSG.lock().unwrap().map(|msg| thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                send_external(id, msg);
+            }));
+            let reason = join(id);
+            if let Some(sender) = sender {
+                sender.join().unwrap();
+            }
+            *LONE.lock().unwrap() = Some(reason);
+        }, STACK);
+        LONE.lock().unwrap().take().unwrap()
+    }
+
+    #[test]
+    fn deadlock_action_panic_ends_the_receiver() {
+        let _s = serial();
+        assert_eq!(lone_receive(DeadlockAction::Panic, None), ExitReason::Panicked);
+    }
+
+    #[test]
+    fn deadlock_action_return_none_returns() {
+        let _s = serial();
+        assert_eq!(lone_receive(DeadlockAction::ReturnNone, None), ExitReason::Normal(0));
+    }
+
+    #[test]
+    fn deadlock_action_park_waits_for_an_external_send() {
+        let _s = serial();
+        assert_eq!(lone_receive(DeadlockAction::Park(100), Some(4)), ExitReason::Normal(5));      // 20ms 後の send_external は 100 round 以内に届く
+    }
+
+    static RUNNABLE: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn runnable_others_matches_queue_ids() {
+        let _s = serial();
+        spawn_from_main(|| {
+            let mut seen = vec![runnable_others()];
+            let workers: Vec<u64> = (0..3).map(|_| spawn(spin, STACK)).collect();
+            let ids = queue_ids();
+            seen.push((ids[0] == current_id()) as usize);       // 実行中の actor が先頭
+            seen.push(ids.len() - 1);
+            seen.push(runnable_others());
+            for id in workers {
+                kill(id);
+            }
+            seen.push(runnable_others());
+            *RUNNABLE.lock().unwrap() = seen;
+        }, STACK);
+        assert_eq!(*RUNNABLE.lock().unwrap(), [0, 1, 3, 3, 0]);
+    }
+
+    #[test]
+    fn the_running_actor_is_always_at_queue_position_zero() {
+        let _s = serial();
+        with_runtime(|| {
+            let workers: Vec<u64> = (0..3).map(|_| spawn(spin, STACK)).collect();
+            for _ in 0..3 {
+                assert_eq!(queue_position(), 0);
+                assert_eq!(queue_ids()[queue_position()], current_id());
+                yield_now();        // 末尾に回っても、再び実行されるときは先頭
+            }
+            for id in workers {
+                kill(id);
+            }
+        });
+    }
+
+    static HITS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn parallel_for_runs_each_index_once() {
+        let _s = serial();
+        *HITS.lock().unwrap() = vec![0; 1000];
+        spawn_from_main(|| {
+            parallel_for(1000, 100, |i| {
+                HITS.lock().unwrap()[i] += 1;
+            });
+            HITS.lock().unwrap().push(usize::MAX);      // 戻った時点で全ての chunk が完了している
+        }, STACK);
+        let hits = HITS.lock().unwrap();
+        assert!(hits[..1000].iter().all(|n| *n == 1));
+        assert_eq!(hits[1000..], [usize::MAX]);
+    }
+
+    static CHUNKS: Mutex<(usize, usize)> = Mutex::new((0, 0));     // (f に入った回数, f を抜けた回数)
+
+    #[test]
+    fn parallel_for_survives_a_killed_caller() {
+        let _s = serial();
+        *CHUNKS.lock().unwrap() = (0, 0);
+        spawn_from_main(|| {
+            let caller = spawn_fn(|| {
+                parallel_for(10, 1, |_| {
+                    CHUNKS.lock().unwrap().0 += 1;
+                    yield_now();
+                    CHUNKS.lock().unwrap().1 += 1;     // 呼び出し元が kill された後も f が生きている
+                });
+                0
+            }, STACK);
+            yield_now();
+            assert!(kill(caller));
+            while live_actors().len() > 1 {     // 残った chunk の終了を待つ
+                yield_now();
+            }
+        }, STACK);
+        let (entered, left) = *CHUNKS.lock().unwrap();
+        assert!(entered > 0);
+        assert_eq!(entered, left);
+    }
+
+    static KIND: Mutex<MailboxKind> = Mutex::new(MailboxKind::Fifo);
+    static QUEUED: Mutex<Vec<(usize, u64)>> = Mutex::new(Vec::new());
+
+    // mailbox に残っている message を受け取った順に桁として並べる
+    fn queued_digits() {
+        let queued = unsafe {(*MESSAGES).len(current_id())};
+        let mut n = 0;
+        for _ in 0..queued {
+            n = n * 10 + receive().unwrap();
+        }
+        QUEUED.lock().unwrap().push((queued, n));
+    }
+
+    // mailbox の種類だけを変えて、実行前に 1, 2, 3 を送ったときに残っている message
+    fn receive_after_three_sends(kind: MailboxKind) -> (usize, u64) {
+        *KIND.lock().unwrap() = kind;
+        QUEUED.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let kind = *KIND.lock().unwrap();
+            let id = ActorBuilder::new().stack(STACK).mailbox(kind).suspended().spawn(queued_digits);
+            for msg in 1..=3 {
+                send(id, msg);
+            }
+            resume(id);
+        }, STACK);
+        QUEUED.lock().unwrap()[0]
+    }
+
+    #[test]
+    fn a_ring_mailbox_drops_the_oldest_while_fifo_keeps_all() {
+        let _s = serial();
+        assert_eq!(receive_after_three_sends(MailboxKind::Ring(2)), (2, 23));       // 最も古い 1 が捨てられる
+        assert_eq!(receive_after_three_sends(MailboxKind::Fifo), (3, 123));
+    }
+
+    static RING_ACKS: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn a_tracked_message_evicted_from_a_ring_mailbox_fails() {
+        let _s = serial();
+        RING_ACKS.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let receiver = ActorBuilder::new().stack(STACK).mailbox(MailboxKind::Ring(1)).suspended().spawn(wait_one);
+            let first = send_tracked(receiver, 1);
+            let second = send_tracked(receiver, 2);     // first を押し出す
+            resume(receiver);
+            let acks = vec![wait_ack(first), wait_ack(second)];
+            *RING_ACKS.lock().unwrap() = acks;
+        }, STACK);
+        assert_eq!(*RING_ACKS.lock().unwrap(), [false, true]);
+    }
+
+    fn two_digits() {
+        log(receive().unwrap() * 10 + receive().unwrap());
+    }
+
+    #[test]
+    fn a_parked_receiver_sees_messages_in_send_order() {
+        let _s = serial();
+        take_log();
+        spawn_from_main(|| {
+            let c = spawn(two_digits, STACK);       // c は受信待ち
+            spawn_fn(move || { send(c, 1); 0 }, STACK);     // c を起こして yield
+            spawn_fn(move || { send(c, 2); 0 }, STACK);
+        }, STACK);
+        assert_eq!(take_log(), [12]);
+    }
+
+    // root actor: 子を 1 つ生成し、自分と子の両方が log に記録する
+    fn root_with_child() {
+        log(1);
+        spawn_fn(|| { log(2); 0 }, STACK);
+    }
+
+    #[test]
+    fn run_all_runs_every_root_and_tears_down() {
+        let _s = serial();
+        take_log();
+        run_all(&[(root_with_child, STACK), (root_with_child, STACK), (root_with_child, STACK)]);
+        let mut marks = take_log();
+        marks.sort();
+        assert_eq!(marks, vec![1, 1, 1, 2, 2, 2]);      // 3 つの root とその子がすべて完了してから戻る
+        unsafe {assert_globals_reset()};
+    }
+
+    static RAN: AtomicBool = AtomicBool::new(false);
+    static PEER_RAN: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    fn witness() {
+        loop {
+            RAN.store(true, Ordering::SeqCst);
+            yield_now();
+        }
+    }
+
+    // ROUNDS 個の message が溜まった actor がすべて受け取り終えるまでに、隣の witness が実行されたか
+    fn peer_ran_during_flood(budget: usize) -> bool {
+        set_config(RuntimeConfig { receive_budget: budget, send_yields: false, ..RuntimeConfig::new() });
+        PEER_RAN.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let flooded = spawn_fn(|| {
+                for _ in 0..ROUNDS {
+                    receive();
+                }
+                RAN.load(Ordering::SeqCst) as u64
+            }, STACK);
+            for msg in 0..ROUNDS {
+                send(flooded, msg);
+            }
+            RAN.store(false, Ordering::SeqCst);
+            let peer = spawn(witness, STACK);       // flooded は先に起こされているため peer より先に実行される
+            let ran = join(flooded) == ExitReason::Normal(1);
+            kill(peer);
+            PEER_RAN.lock().unwrap().push(ran);
+        }, STACK);
+        PEER_RAN.lock().unwrap()[0]
+    }
+
+    #[test]
+    fn a_receive_budget_lets_a_starved_peer_run() {
+        let _s = serial();
+        assert!(peer_ran_during_flood(10));
+        assert!(!peer_ran_during_flood(usize::MAX));        // budget がなければ mailbox が空になるまで独占する
+    }
+
+    // spawn の直後に 3 つの文を実行したとき、子 (2) と生成元 (1) が記録される順
+    fn order_after_spawn(spawn_yields: bool) -> Vec<u64> {
+        take_log();
+        set_config(RuntimeConfig { spawn_yields, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let child = spawn_fn(|| { log(2); 0 }, STACK);
+            for _ in 0..3 {
+                log(1);
+            }
+            join(child);
+        }, STACK);
+        take_log()
+    }
+
+    #[test]
+    fn spawn_yields_decides_who_runs_after_spawn() {
+        let _s = serial();
+        assert_eq!(order_after_spawn(true), vec![2, 1, 1, 1]);      // spawn で子に切り替わる
+        assert_eq!(order_after_spawn(false), vec![1, 1, 1, 2]);     // 生成元が実行を続ける
+    }
+
+    static PARENTS: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn parent_of_reports_the_spawning_actor() {
+        let _s = serial();
+        PARENTS.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let parent = spawn_fn(|| {
+                let children: Vec<u64> = (0..2).map(|_| spawn(wait_one, STACK)).collect();
+                let me = current_id();
+                PARENTS.lock().unwrap().push(children.iter().all(|child| parent_of(*child) == Some(me)));
+                for child in children {
+                    send(child, 0);
+                    join(child);
+                }
+                0
+            }, STACK);
+            PARENTS.lock().unwrap().push(parent_of(parent) == Some(current_id()));
+            join(parent);
+        }, STACK);
+        assert_eq!(*PARENTS.lock().unwrap(), [true, true]);
+    }
+
+    static TREE: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn kill_tree_removes_every_descendant() {
+        let _s = serial();
+        TREE.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let root = spawn_fn(|| {
+                spawn_fn(|| {
+                    spawn(wait_one, STACK);     // 3 段目
+                    receive();
+                    0
+                }, STACK);
+                spawn(wait_one, STACK);
+                receive();
+                0
+            }, STACK);
+            yield_now();
+            let before = live_actors().len();       // 呼び出し元 + root + 子 2 つ + 孫 1 つ
+            kill_tree(root);
+            let after = live_actors();
+            *TREE.lock().unwrap() = vec![before, after.len(), (after == [current_id()]) as usize];
+        }, STACK);
+        assert_eq!(*TREE.lock().unwrap(), [5, 1, 1]);
+    }
+
+    static BYTE_CAP: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn byte_cap_rejects_and_counts_messages_over_the_limit() {
+        let _s = serial();
+        BYTE_CAP.lock().unwrap().clear();
+        set_config(RuntimeConfig { send_yields: false, max_mailbox_bytes: 3 * MESSAGE_BYTES, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let receiver = spawn_fn(|| receive().unwrap() + receive().unwrap() + receive().unwrap(), STACK);
+            let accepted = (0..3).filter(|i| try_send(receiver, *i).is_ok()).count() as u64;
+            let bytes = mailbox_bytes(receiver) as u64;
+            let full = matches!(try_send(receiver, 3), Err(ActorError::MailboxFull { bytes, limit, .. }) if bytes == limit);
+            send(receiver, 4);      // 上限を超える send は捨てられて数えられる
+            let record = vec![accepted, bytes, full as u64, dropped_messages(receiver), mailbox_bytes(receiver) as u64];
+            let exit = join(receiver);
+            let mut log = BYTE_CAP.lock().unwrap();
+            *log = record;
+            log.push((exit == ExitReason::Normal(3)) as u64);
+        }, STACK);
+        let m = MESSAGE_BYTES as u64;
+        assert_eq!(*BYTE_CAP.lock().unwrap(), [3, 3 * m, 1, 1, 3 * m, 1]);
+    }
+
+    #[test]
+    fn large_messages_hit_the_byte_cap_before_small_ones() {
+        let _s = serial();
+        let m = MESSAGE_BYTES;
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, max_mailbox_bytes: 16 * m, ..RuntimeConfig::new() });
+        with_runtime(move || {
+            let receiver = ActorBuilder::new().stack(STACK).suspended().spawn(wait_one);
+            assert!((0..10).all(|i| try_send(receiver, i).is_ok()));        // 小さい message は 10 個入る
+            assert_eq!(mailbox_bytes(receiver), 10 * m);
+            assert_eq!(try_send_sized(receiver, 10, 8 * m), Err(ActorError::MailboxFull { id: receiver, bytes: 10 * m, limit: 16 * m }));
+            assert_eq!(try_send_sized(receiver, 11, 6 * m), Ok(0));     // ちょうど上限まで
+            assert!(try_send(receiver, 12).is_err());
+            send_sized(receiver, 13, 8 * m);        // 上限を超える send は捨てて数える
+            assert_eq!((mailbox_len(receiver), mailbox_bytes(receiver), dropped_messages(receiver)), (11, 16 * m, 1));
+            assert!(resume(receiver));
+            join(receiver);
+            assert_eq!(mailbox_bytes(receiver), 15 * m);        // 受信した 1 個の分だけ減る
+
+            let ring = ActorBuilder::new().stack(STACK).mailbox(MailboxKind::Ring(1)).suspended().spawn(wait_one);
+            send_sized(ring, 1, 4 * m);
+            send(ring, 2);      // 大きい方を追い出す
+            assert_eq!(mailbox_bytes(ring), m);
+            assert!(resume(ring));
+            join(ring);
+        });
+    }
+
+    static HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn the_schedule_hook_runs_once_per_context_switch() {
+        let _s = serial();
+        HOOK_CALLS.store(0, Ordering::SeqCst);
+        spawn_from_main(|| {
+            let spinner = spawn(spin, STACK);
+            set_on_schedule(Box::new(|| { HOOK_CALLS.fetch_add(1, Ordering::SeqCst); }));
+            for _ in 0..ROUNDS {
+                yield_now();        // spinner へ切り替わり、spinner の yield で戻る
+            }
+            clear_on_schedule();
+            kill(spinner);
+        }, STACK);
+        assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 2 * ROUNDS as usize);
+    }
+
+    static DRAINED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn accessors_treat_a_drained_mailbox_as_empty() {
+        let _s = serial();
+        DRAINED.lock().unwrap().clear();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let me = current_id();
+            send(me, 1);
+            let mut seen = vec![peek().unwrap_or(0), receive().unwrap()];     // mailbox が空になり MappedList から取り除かれる
+            seen.push(mailbox_len(me) as u64);
+            seen.push(mailbox_bytes(me) as u64);
+            seen.push(peek().is_none() as u64);
+            seen.push(poll_messages() as u64);
+            seen.push(unsafe {(*MESSAGES).len(me)} as u64);
+            *DRAINED.lock().unwrap() = seen;
+        }, STACK);
+        assert_eq!(*DRAINED.lock().unwrap(), [1, 1, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn a_result_handle_can_be_consumed_by_another_actor() {
+        let _s = serial();
+        EXITS.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let (_, handle) = spawn_with_result(|| { yield_now(); 6 * 7 }, STACK);
+            let consumer = spawn_fn(move || handle.get().unwrap() + 1, STACK);       // handle ごと別の actor に渡す
+            let exit = join(consumer);
+            EXITS.lock().unwrap().push(exit);
+        }, STACK);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(43)]);
+    }
+
+    static IN_SESSION: AtomicBool = AtomicBool::new(false);
+
+    #[test]
+    fn in_runtime_is_true_only_during_a_session() {
+        let _s = serial();
+        assert!(!in_runtime());
+        IN_SESSION.store(false, Ordering::SeqCst);
+        spawn_from_main(|| IN_SESSION.store(in_runtime(), Ordering::SeqCst), STACK);
+        assert!(IN_SESSION.load(Ordering::SeqCst));
+        assert!(!in_runtime());
+    }
+
+    fn first_behaviour() {
+        log(receive().unwrap());
+        become_fn(second_behaviour);
+    }
+
+    fn second_behaviour() {
+        log(receive().unwrap() * 10);       // 同じ mailbox の続きを新しい振る舞いで処理する
+    }
+
+    #[test]
+    fn become_keeps_processing_the_same_mailbox() {
+        let _s = serial();
+        take_log();
+        EXITS.lock().unwrap().clear();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let id = spawn(first_behaviour, STACK);
+            send(id, 1);
+            send(id, 2);
+            let exit = join(id);
+            EXITS.lock().unwrap().push(exit);
+        }, STACK);
+        assert_eq!(*EXITS.lock().unwrap(), [ExitReason::Normal(0)]);
+        assert_eq!(take_log(), vec![1, 20]);
+    }
+
+    static GUARDED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn an_actor_guard_kills_its_actor_at_the_end_of_the_block() {
+        fn noop() {}
+        let _s = serial();
+        GUARDED.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let id = {
+                let guard = spawn_guarded(spin, STACK);
+                yield_now();
+                GUARDED.lock().unwrap().push(is_alive(guard.id()));
+                guard.id()
+            };      // guard の drop で kill される
+            let alive = is_alive(id);
+            let killed = join(id) == ExitReason::Killed;
+            GUARDED.lock().unwrap().extend([alive, killed]);
+            let finished = spawn_guarded(noop, STACK);
+            join(finished.id());
+            drop(finished);     // 終了済みなら何もしない
+        }, STACK);
+        assert_eq!(*GUARDED.lock().unwrap(), [true, false, true]);
+    }
+
+    #[test]
+    fn killing_a_waiting_actor_drops_its_wait_registrations() {
+        let _s = serial();
+        with_runtime(|| {
+            let target = spawn(spin, STACK);        // join も reply も返さない
+            let token = CancelToken::new();
+            let waiters = [
+                spawn_fn(move || { join(target); 0 }, STACK),
+                spawn_fn(move || receive_cancellable(token).unwrap_or(0), STACK),
+                spawn_fn(move || try_call_with_correlation(target, 1).map_or(0, |(_, r)| r), STACK),
+            ];
+            yield_now();        // 3 つとも待機に入る
+            unsafe {
+                assert!(!(*JOINERS).is_empty() && !(*TOKEN_WAITERS).is_empty() && !(*PENDING_REPLIES).is_empty());
+            }
+            for id in waiters {
+                kill(id);
+            }
+            unsafe {
+                assert!((*JOINERS).is_empty() && (*TOKEN_WAITERS).is_empty() && (*PENDING_REPLIES).is_empty());
+            }
+            kill(target);
+        });
+    }
+
+    // (corr_id, request) を受け取り、delay 回 yield してから request + 1 を reply する
+    fn replier(delay: u64) -> u64 {
+        let (corr, request) = receive_correlated();
+        for _ in 0..delay {
+            yield_now();
+        }
+        reply_correlated(corr, request + 1);
+        0
+    }
+
+    static TIMEOUTS: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn join_and_call_time_out_after_their_rounds() {
+        fn noop() {}
+        let _s = serial();
+        TIMEOUTS.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let mut seen = Vec::new();
+            let quick = spawn(noop, STACK);
+            seen.push(join_timeout(quick, 10) == Some(ExitReason::Normal(0)));
+            let endless = spawn(spin, STACK);
+            seen.push(join_timeout(endless, 10).is_none());
+            kill(endless);
+            let server = spawn_fn(|| replier(0), STACK);
+            seen.push(call_timeout(server, 1, 10) == Some(2));
+            let slow = spawn_fn(|| replier(ROUNDS), STACK);
+            seen.push(call_timeout(slow, 1, 10).is_none());
+            join(slow);     // 諦めた後の reply は捨てられる
+            seen.push(mailbox_len(current_id()) == 0);
+            seen.push(unsafe {(*REPLIES).is_empty() && (*PENDING_REPLIES).is_empty()});
+            *TIMEOUTS.lock().unwrap() = seen;
+        }, STACK);
+        assert_eq!(*TIMEOUTS.lock().unwrap(), [true; 6]);
+    }
+
+    #[cfg(feature = "debug_internals")]
+    static SAVED_SP: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[cfg(feature = "debug_internals")]
+    #[test]
+    fn a_parked_actor_saves_an_sp_inside_its_stack() {
+        let _s = serial();
+        SAVED_SP.lock().unwrap().clear();
+        spawn_from_main(|| {
+            let id = spawn(wait_one, STACK);        // 受信待ちになり、レジスタが保存される
+            let (low, high) = stack_range(id).unwrap();
+            let sp = saved_regs(id).unwrap().sp as usize;
+            let running = saved_regs(current_id()).is_none() as usize;     // 実行中の actor のレジスタは CPU 上にある
+            *SAVED_SP.lock().unwrap() = vec![low, sp, high, running];
+            send(id, 0);
+            join(id);
+        }, STACK);
+        let saved = SAVED_SP.lock().unwrap();
+        let (low, sp, high) = (saved[0], saved[1], saved[2]);
+        assert!(low < sp && sp <= high, "sp {:#x} outside {:#x}..={:#x}", sp, low, high);
+        assert_eq!(saved[3], 1);
+    }
+
+    static DEFERRED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn a_reschedule_requested_inside_a_critical_section_is_deferred() {
+        let _s = serial();
+        DEFERRED.lock().unwrap().clear();
+        spawn_from_main(|| unsafe {
+            let w = spawn(witness, STACK);
+            let queue = queue_ids();
+            RAN.store(false, Ordering::SeqCst);
+            enter_critical("test");
+            request_reschedule();       // signal handler から呼ばれた想定
+            safe_point();
+            let mut seen = vec![RAN.load(Ordering::SeqCst), queue_ids() == queue];     // critical section 中は切り替えない
+            IN_SCHEDULER = false;
+            safe_point();
+            seen.push(RAN.load(Ordering::SeqCst));      // 抜けた後の safe point で切り替わる
+            *DEFERRED.lock().unwrap() = seen;
+            kill(w);
+        }, STACK);
+        assert_eq!(*DEFERRED.lock().unwrap(), [false, true, true]);
+    }
+
+    static RESCHEDULED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn every_send_honours_a_requested_reschedule_without_send_yields() {
+        let _s = serial();
+        RESCHEDULED.lock().unwrap().clear();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let sink = spawn(pool_worker, STACK);
+            let pool = spawn_pool(pool_worker, STACK, 1);
+            let w = spawn(witness, STACK);
+            let ops: Vec<Box<dyn Fn()>> = vec![
+                Box::new(move || { send(sink, 1); }),
+                Box::new(move || { broadcast(&[sink], 2); }),
+                Box::new(move || send_prio(sink, 3, 1)),
+                Box::new(move || { send_tracked(sink, 4); }),
+                Box::new(move || pool_submit(pool, 6)),
+                Box::new(|| reply_correlated(0, 7)),      // 待っている caller のいない reply
+            ];
+            for op in ops {
+                RAN.store(false, Ordering::SeqCst);
+                request_reschedule();
+                op();
+                RESCHEDULED.lock().unwrap().push(RAN.load(Ordering::SeqCst));      // 配送の後の safe point で切り替わった
+            }
+            kill(w);
+            shutdown();
+        }, STACK);
+        assert_eq!(*RESCHEDULED.lock().unwrap(), [true; 6]);
+    }
+
+    static DRAINED_ALL: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn drain_all_empties_every_mailbox() {
+        let _s = serial();
+        DRAINED_ALL.lock().unwrap().clear();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let ids: Vec<u64> = (0..3).map(|_| spawn(wait_one, STACK)).collect();
+            let mut expected = HashMap::new();
+            for (n, id) in ids.iter().enumerate() {
+                let msgs: Vec<u64> = (0..=n as u64).collect();
+                for msg in &msgs {
+                    send(*id, *msg);
+                }
+                expected.insert(*id, msgs);
+            }
+            let seen = vec![
+                drain_all() == expected,
+                ids.iter().all(|id| mailbox_len(*id) == 0),
+                drain_all().is_empty(),
+            ];
+            *DRAINED_ALL.lock().unwrap() = seen;
+            for id in ids {
+                kill(id);
+            }
+        }, STACK);
+        assert_eq!(*DRAINED_ALL.lock().unwrap(), [true; 3]);
+    }
+
+    static OWNED_BUF: AtomicUsize = AtomicUsize::new(0);     // 呼び出し側が確保したスタックの先頭
+    static OWNED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn a_caller_owned_stack_is_not_freed_by_the_runtime() {
+        let _s = serial();
+        let layout = Layout::from_size_align(STACK, PAGE_SIZE).unwrap();
+        let buf = unsafe {std::alloc::alloc(layout)};
+        unsafe {ptr::write_bytes(buf, 0x5A, 64)};       // 最下位 (actor が使わない領域) に目印を書く
+        OWNED_BUF.store(buf as usize, Ordering::SeqCst);
+        spawn_from_main(|| {
+            fn noop() {}
+            let total = unsafe {STACK_TOTAL};
+            let id = unsafe {spawn_with_stack(noop, OWNED_BUF.load(Ordering::SeqCst) as *mut u8, STACK)};
+            let counted = unsafe {STACK_TOTAL} - total;     // 呼び出し側のスタックは数えない
+            let normal = join(id) == ExitReason::Normal(0);
+            *OWNED.lock().unwrap() = vec![counted, normal as usize];
+        }, STACK);
+        assert_eq!(*OWNED.lock().unwrap(), vec![0, 1]);
+        unsafe {
+            assert!(std::slice::from_raw_parts(buf, 64).iter().all(|b| *b == 0x5A));
+            std::alloc::dealloc(buf, layout);       // runtime が解放していれば二重解放になる
+        }
+    }
+
+    static CANARY: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn the_top_of_stack_does_not_touch_the_next_allocation() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, ..RuntimeConfig::new() });      // 実行前の sp を見る
+        let layout = Layout::from_size_align(2 * STACK, 16).unwrap();
+        let buf = unsafe {std::alloc::alloc(layout)};
+        let neighbour = unsafe {buf.add(STACK)};        // 直上の領域 (隣の actor のスタック) に canary を書いておく
+        unsafe {ptr::write_bytes(neighbour, 0xAA, STACK)};
+        OWNED_BUF.store(buf as usize, Ordering::SeqCst);
+        spawn_from_main(|| {
+            let id = unsafe {spawn_with_stack(call_leaf, OWNED_BUF.load(Ordering::SeqCst) as *mut u8, STACK)};
+            let (_, top) = stack_range(id).unwrap();
+            let gap = top - unsafe {find_context(id)}.unwrap().regs.sp as usize;
+            let normal = join(id) == ExitReason::Normal(0);
+            *CANARY.lock().unwrap() = vec![top, gap, normal as usize];
+        }, STACK);
+        let canary = CANARY.lock().unwrap().clone();
+        assert_eq!(canary[0], neighbour as usize);
+        assert!(canary[1] >= RED_ZONE, "{:?}", canary);     // 最上位に余白を残して開始する
+        assert_eq!(canary[2], 1);
+        unsafe {
+            assert!(std::slice::from_raw_parts(neighbour, STACK).iter().all(|b| *b == 0xAA));
+            std::alloc::dealloc(buf, layout);
+        }
+    }
+
+    #[test]
+    fn yield_to_runs_the_target_next() {
+        let _s = serial();
+        take_log();
+        set_config(RuntimeConfig { spawn_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let unrelated = spawn_fn(|| { log(1); 0 }, STACK);      // queue では b より前
+            let b = spawn_fn(|| { log(2); 0 }, STACK);
+            let switched = yield_to(b);
+            log(3);
+            join(unrelated);
+            log(switched as u64);
+            log(yield_to(b) as u64 + yield_to(current_id()) as u64);        // 終了済みと自分自身は false
+        }, STACK);
+        assert_eq!(take_log(), vec![2, 1, 3, 1, 0]);      // b の後は queue の順
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn spawning_one_function_a_thousand_times_warns() {
+        if in_child() {
+            let _s = serial();
+            spawn_from_main(|| {
+                fn noop() {}
+                for _ in 0..1000 {
+                    spawn(noop, STACK);
+                }
+            }, STACK);
+            return;
+        }
+        let out = run_child("spawning_one_function_a_thousand_times_warns");        // 警告は stderr に出る
+        assert!(out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("has been spawned 1000 times"), "{}", stderr);
+        assert_eq!(stderr.matches("possible runaway spawn loop").count(), 1);       // 閾値で一度だけ
+    }
+
+    static RESTORED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    fn checkpoint_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("green_checkpoint_{}", process::id()))
+    }
+
+    #[test]
+    fn a_restored_mailbox_delivers_the_same_messages_in_order() {
+        let _s = serial();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let me = current_id();
+            for msg in 1..=3 {
+                send(me, msg);
+            }
+            checkpoint_mailbox(me, &checkpoint_path()).unwrap();
+            for _ in 0..3 {
+                receive();      // mailbox を空にする
+            }
+            let mut restored = vec![mailbox_len(me) as u64, restore_mailbox(me, &checkpoint_path()).unwrap() as u64];
+            restored.extend((0..3).map(|_| receive().unwrap()));
+            *RESTORED.lock().unwrap() = restored;
+        }, STACK);
+        fs::remove_file(checkpoint_path()).unwrap();
+        assert_eq!(*RESTORED.lock().unwrap(), vec![0, 3, 1, 2, 3]);
+    }
+
+    // 受け取った値 + 1 を送信元 (親) に返し続ける
+    fn echo_to_parent() {
+        let parent = parent_of(current_id()).unwrap();
+        loop {
+            let n = receive().unwrap();
+            send(parent, n + 1);        // send の yield で caller に切り替わりうる
+        }
+    }
+
+    #[test]
+    fn send_then_receive_loses_no_reply() {
+        let _s = serial();
+        with_runtime(|| {
+            let me = current_id();
+            let echo = spawn(echo_to_parent, STACK);
+            let spinner = spawn(spin, STACK);       // 送信と受信待ちの間に割り込む機会を与える
+            for i in 0..ROUNDS {
+                let before = run_count(me);
+                assert_eq!(send_then_receive(echo, i), i + 1);
+                assert_eq!(run_count(me) - before, 1);      // reply による起床の 1 回だけ再開する
+            }
+            assert_eq!(mailbox_len(me), 0);
+            kill(spinner);
+            kill(echo);
+        });
+    }
+
+    fn exits() {}
+
+    fn panics() {
+        panic!("boom");
+    }
+
+    #[test]
+    fn sessions_leave_no_leaks() {
+        let _s = serial();
+        set_panic_handler(Box::new(|_, _| {}));
+        set_config(RuntimeConfig { spawn_yields: false, ..RuntimeConfig::new() });
+        let workloads: [fn(); 4] = [
+            || { spawn(exits, STACK); },      // spawn して終了
+            || { spawn(panics, STACK); },
+            || {
+                let id = spawn(spin, STACK);
+                kill(id);
+            },
+            || {
+                let id = spawn(exits, STACK);
+                join(id);
+            },
+        ];
+        for workload in workloads {
+            spawn_from_main(workload, STACK);
+            assert_no_leaks();
+        }
+    }
+
+    #[test]
+    fn assert_no_leaks_reports_an_unfinished_session() {
+        let _s = serial();
+        spawn_from_main_with_budget(spin, STACK, 3);     // 終了しない actor を teardown が破棄する
+        assert!(panic::catch_unwind(assert_no_leaks).is_err());
+        spawn_from_main(|| {}, STACK);       // 次の session が leak なしで終われば記録は消える
+        assert_no_leaks();
+    }
+
+    static DEPTHS: Mutex<Vec<(u64, usize)>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn receive_with_depth_reports_what_is_left() {
+        let _s = serial();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let me = current_id();
+            for msg in 1..=3 {
+                send(me, msg);
+            }
+            *DEPTHS.lock().unwrap() = (0..3).map(|_| receive_with_depth().unwrap()).collect();
+        }, STACK);
+        assert_eq!(*DEPTHS.lock().unwrap(), vec![(1, 2), (2, 1), (3, 0)]);
+    }
+
+    static ECHOED: AtomicBool = AtomicBool::new(false);
+
+    /*  switch_context から戻ってしまう ABI の不具合は abort になる (process ごと落ちる) ため、子 process で確かめる
+        yield, 受信待ち, 終了のそれぞれの経路で切り替えても、戻り先の abort に到達しない
+    */
+    #[test]
+    fn context_switches_never_return_into_the_abort() {
+        if in_child() {
+            let _s = serial();
+            spawn_from_main(|| {
+                let echo = spawn(echo_to_parent, STACK);
+                let echoed = (0..ROUNDS).all(|i| {
+                    send(echo, i);
+                    let reply = receive();
+                    join(spawn(exits, STACK));
+                    reply == Some(i + 1)
+                });
+                ECHOED.store(echoed, Ordering::SeqCst);
+                kill(echo);
+            }, STACK);
+            if ECHOED.load(Ordering::SeqCst) {
+                println!("switched cleanly");
+            }
+            return;
+        }
+        let out = run_child("context_switches_never_return_into_the_abort");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(out.status.success(), "{}", stderr);
+        assert!(!stderr.contains("switch_context returned to its caller"));
+        assert!(String::from_utf8_lossy(&out.stdout).contains("switched cleanly"));
+    }
+
+    static STEERED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn send_to_least_loaded_steers_to_the_emptiest_worker() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let workers: Vec<u64> = (0..3).map(|_| spawn(wait_one, STACK)).collect();      // まだ実行されないので受信しない
+            for (n, id) in workers.iter().enumerate() {
+                join_group("workers", *id);
+                for _ in 0..2 - n {
+                    send(*id, 0);       // backlog: 2, 1, 0
+                }
+            }
+            let mut steered = vec![(send_to_least_loaded("workers", 1) == Some(workers[2])) as usize];
+            for _ in 0..5 {
+                send_to_least_loaded("workers", 1);
+            }
+            steered.extend(workers.iter().map(|id| mailbox_len(*id)));
+
+            kill(workers[0]);
+            steered.push((send_to_least_loaded("workers", 1) != Some(workers[0])) as usize);        // 終了した member は取り除かれる
+            steered.push(group_members("workers").len());
+            for id in &workers[1..] {
+                kill(*id);
+            }
+            *STEERED.lock().unwrap() = steered;
+        }, STACK);
+        assert_eq!(*STEERED.lock().unwrap(), vec![1, 3, 3, 3, 1, 2]);
+    }
+
+    static AGES: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn an_older_actor_reports_a_greater_age() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, ..RuntimeConfig::new() });
+        spawn_from_main(|| {
+            let older = spawn(spin, STACK);
+            yield_now();        // 論理時計を進める
+            let newer = spawn(spin, STACK);
+            let mut ages = (actor_age(older).unwrap(), actor_age(newer).unwrap());
+            let mut checks = vec![ages.0 > ages.1];
+            for _ in 0..ROUNDS {
+                yield_now();
+                let now = (actor_age(older).unwrap(), actor_age(newer).unwrap());
+                checks.push(now.0 > ages.0 && now.1 > ages.1);      // yield ごとに単調に増える
+                checks.push(now.0 - now.1 == ages.0 - ages.1);
+                ages = now;
+            }
+            kill(older);
+            kill(newer);
+            checks.push(actor_age(older).is_none());
+            *AGES.lock().unwrap() = checks;
+        }, STACK);
+        let checks = AGES.lock().unwrap().clone();
+        assert_eq!(checks.len(), 2 + 2 * ROUNDS as usize);
+        assert!(checks.iter().all(|ok| *ok), "{:?}", checks);
+    }
+
+    #[test]
+    fn with_runtime_runs_straight_line_actor_code() {
+        let _s = serial();
+        let total = with_runtime(|| {
+            let echo = spawn(echo_to_parent, STACK);
+            let total = (0..3).map(|i| { send(echo, i); receive().unwrap() }).sum::<u64>();
+            kill(echo);
+            total
+        });
+        assert_eq!(total, 6);       // closure の返り値がそのまま返る
+        assert!(!in_runtime());
+    }
+
+    #[test]
+    fn an_actor_that_runs_twice_as_often_has_double_the_run_count() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let often = spawn(spin, STACK);       // 毎回実行可能
+            let seldom = spawn_fn(|| loop { receive(); }, STACK);      // message が届いた回だけ実行可能
+            for i in 0..2 * ROUNDS {
+                if i % 2 == 0 {
+                    send(seldom, i);
+                }
+                yield_now();
+            }
+            let (a, b) = (run_count(often), run_count(seldom));
+            assert!(a >= 2 * b - 2 && a <= 2 * b + 2, "often {} seldom {}", a, b);
+            kill(often);
+            kill(seldom);
+        });
+    }
+
+    #[test]
+    fn a_preempt_disabled_section_defers_the_requested_yield() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let w = spawn(witness, STACK);
+            let sink = spawn(spin, STACK);
+            RAN.store(false, Ordering::SeqCst);
+            preempt_disable();
+            preempt_disable();      // 入れ子
+            request_reschedule();       // signal handler から呼ばれた想定
+            for i in 0..3 {
+                send(sink, i);      // send の後の safe point でも切り替えない
+            }
+            preempt_enable();
+            assert!(!RAN.load(Ordering::SeqCst));
+            preempt_enable();       // 最後の preempt_enable で遅延していた yield を行う
+            assert!(RAN.load(Ordering::SeqCst));
+            kill(w);
+            kill(sink);
+        });
+    }
+
+    #[test]
+    fn a_spawn_try_error_reaches_the_joiner_with_its_message() {
+        let _s = serial();
+        with_runtime(|| {
+            let failing = spawn_try(|| Err::<(), _>(format!("disk {} is full", 3)), STACK);
+            let fine = spawn_try(|| Ok::<(), String>(()), STACK);
+            let joiner = spawn_fn(move || match join(failing) {
+                ExitReason::Error(msg) if msg == "\"disk 3 is full\"" => 1,     // Debug 表現のまま保持される
+                _ => 0,
+            }, STACK);
+            assert_eq!(join(joiner), ExitReason::Normal(1));
+            assert_eq!(join(fine), ExitReason::Normal(0));
+        });
+    }
+
+    #[test]
+    fn the_identity_hasher_keeps_colliding_ids_apart() {
+        // 下位 bit だけが異なる id と上位 bit だけが異なる id: そのままの値を hash にすると bucket が偏る
+        let ids: Vec<u64> = (0..1000).map(|i| i << 48).chain((0..1000).map(|i| i + 1)).collect();
+        let mut map: IdMap<u64> = IdMap::default();
+        for id in &ids {
+            assert!(map.insert(*id, !*id).is_none());
+        }
+        assert_eq!(map.len(), ids.len());
+        assert!(ids.iter().all(|id| map.get(id) == Some(&!*id)));
+        for id in ids.iter().step_by(2) {
+            map.remove(id);
+        }
+        assert!(ids.iter().enumerate().all(|(i, id)| map.contains_key(id) == (i % 2 == 1)));
+
+        let mut list = MappedList::new();
+        for id in &ids {
+            list.push_back(*id, *id);
+        }
+        assert!(ids.iter().all(|id| list.len(*id) == 1 && list.pop_front(*id).map(|env| env.msg) == Some(*id)));
+    }
+
+    // 最後に生成した actor が実行されるまでに、先に実行された actor の数
+    fn runs_before_the_last(boosted: bool) -> usize {
+        take_log();
+        set_config(RuntimeConfig { spawn_yields: false, ..RuntimeConfig::new() });
+        with_runtime(move || {
+            let ids: Vec<u64> = (0..8).map(|i| spawn_fn(move || { log(i); 0 }, STACK)).collect();
+            if boosted {
+                assert!(boost(ids[7]));
+            }
+            for id in ids {
+                join(id);
+            }
+        });
+        take_log().iter().position(|mark| *mark == 7).unwrap()
+    }
+
+    #[test]
+    fn boost_reduces_the_time_to_run() {
+        let _s = serial();
+        assert_eq!(runs_before_the_last(false), 7);
+        assert_eq!(runs_before_the_last(true), 3);      // 先頭との中間まで進む (一気に先頭にはしない)
+    }
+
+    #[test]
+    fn send_indexed_returns_each_mailbox_slot() {
+        let _s = serial();
+        take_log();
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let id = spawn(three_digits, STACK);
+            let slots: Vec<Option<usize>> = (1..=3).map(|msg| send_indexed(id, msg)).collect();
+            assert_eq!(slots, vec![Some(0), Some(1), Some(2)]);
+            join(id);
+        });
+        assert_eq!(take_log(), vec![123]);
+    }
+
+    #[test]
+    fn send_indexed_returns_none_for_a_rejected_send() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, max_mailbox_bytes: 2 * MESSAGE_BYTES, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let bounded = ActorBuilder::new().stack(STACK).mailbox_cap(1).suspended().spawn(wait_one);
+            assert_eq!(send_indexed(bounded, 1), Some(0));
+            assert_eq!(send_indexed(bounded, 2), None);     // Bounded(1) が満杯
+            let capped = ActorBuilder::new().stack(STACK).suspended().spawn(wait_one);
+            assert_eq!((1..=3).map(|msg| send_indexed(capped, msg)).collect::<Vec<_>>(), [Some(0), Some(1), None]);     // max_mailbox_bytes
+            for id in [bounded, capped] {
+                assert!(resume(id));
+                join(id);
+            }
+        });
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn waking_without_a_message_ends_in_a_diagnostic() {
+        static LAST_PANIC: Mutex<String> = Mutex::new(String::new());
+        let _s = serial();
+        set_config(RuntimeConfig { max_reparks: 5, ..RuntimeConfig::new() });
+        set_panic_handler(Box::new(|_, payload| {
+            *LAST_PANIC.lock().unwrap() = payload.downcast_ref::<String>().cloned().unwrap_or_default();
+        }));
+        with_runtime(|| {
+            let receiver = spawn(wait_one, STACK);
+            let waker = spawn_fn(move || {
+                while is_alive(receiver) {
+                    unsafe {wake(receiver)};        // message を積まずに起こす (起床処理の不具合の再現)
+                    yield_now();
+                }
+                current_id()
+            }, STACK);
+            assert_eq!(join(receiver), ExitReason::Panicked);       // 止まらない loop にならない
+            let waker = match join(waker) {
+                ExitReason::Normal(id) => id,
+                reason => panic!("unexpected exit: {:?}", reason),
+            };
+            let msg = LAST_PANIC.lock().unwrap().clone();
+            assert!(msg.contains(&format!("actor {} re-parked 5 times", receiver)), "{}", msg);
+            assert!(msg.ends_with(&format!("(last woken by {})", waker)), "{}", msg);
+        });
+    }
+
+    // on_empty で指定される actor: shutdown 中として起こされると receive が None を返す
+    fn reaper() {
+        while receive().is_some() {}
+        log(9);
+    }
+
+    #[test]
+    fn the_reaper_runs_after_every_worker_has_exited() {
+        let _s = serial();
+        take_log();
+        with_runtime(|| {
+            let id = spawn(reaper, STACK);
+            set_config(RuntimeConfig { on_empty: OnEmpty::RunActor(id), ..RuntimeConfig::new() });
+            for _ in 0..3 {
+                spawn_fn(|| { yield_now(); log(1); 0 }, STACK);
+            }
+        });
+        assert_eq!(take_log(), vec![1, 1, 1, 9]);       // 最後の worker の終了後、main() に戻る前に実行される
+    }
+
+    #[test]
+    fn mailbox_handles_have_their_own_ids() {
+        let _s = serial();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let handle = MailboxHandle::new();
+            let id = handle.id();
+            assert!(!is_alive(id) && !live_actors().contains(&id));     // actor としては数えない
+            assert!(panic::catch_unwind(|| join(id)).is_err());      // 待ち続けずに unknown actor で panic
+
+            let ack_id = send_tracked(id, 1);
+            assert!(unsafe {!(*LOST_ACKS).contains(&ack_id)});      // 宛先の mailbox は生きている
+            let clone = handle.clone();
+            assert_eq!(clone.pop(), Some(1));
+            assert!(is_acked(ack_id));
+
+            clone.push(2);
+            drop(handle);
+            assert!(is_mailbox(id));
+            drop(clone);        // 最後の handle: 読まれていない message ごと mailbox を削除し id を返却
+            assert!(!is_mailbox(id));
+            assert_eq!(mailbox_len(id), 0);
+        });
+    }
+
+    #[test]
+    fn a_reentrant_free_deallocates_each_stack_once() {
+        let _s = serial();
+        set_platform(mock_platform());
+        REENTER_ON_FREE.store(true, Ordering::SeqCst);      // 解放の途中で rm_unused_stack に再入する
+        with_runtime(|| {
+            for _ in 0..3 {
+                join(spawn(exits, STACK));      // 終了したスタックは次の切り替えの後に解放される
+            }
+        });
+        assert_eq!(STACK_ALLOCS.load(Ordering::SeqCst), 4);       // root と 3 つの actor
+        assert_eq!(STACK_FREES.load(Ordering::SeqCst), 4);        // 再入しても二度解放しない
+    }
+
+    #[test]
+    fn send_dedup_delivers_a_repeated_id_once() {
+        let _s = serial();
+        QUEUED.lock().unwrap().clear();
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, dedup_window: 2, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let id = spawn(queued_digits, STACK);
+            assert!(send_dedup(id, 1, 100));
+            assert!(!send_dedup(id, 1, 100));       // 同じ dedup_id は捨てる
+            assert!(send_dedup(id, 2, 200));
+            assert!(send_dedup(id, 3, 300));
+            assert!(send_dedup(id, 4, 100));        // window (直近 2 個) から外れた dedup_id は再び受け付ける
+            join(id);
+        });
+        assert_eq!(QUEUED.lock().unwrap().pop(), Some((4, 1234)));
+    }
+
+    // この process で mlock されているメモリ (kB)
+    #[cfg(target_os = "linux")]
+    fn locked_kb() -> usize {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|line| line.starts_with("VmLck:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn an_mlocked_stack_is_resident_until_the_actor_exits() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let before = locked_kb();
+            let id = match spawn_mlocked(wait_one, STACK) {
+                Ok(id) => id,
+                Err(e) => {
+                    eprintln!("skipping: {} (RLIMIT_MEMLOCK is too low)", e);      // best-effort: 固定できない環境では確かめない
+                    return;
+                },
+            };
+            let (low, high) = stack_range(id).unwrap();
+            assert!(locked_kb() >= before + (high - low) / 1024);
+            let mut resident = vec![0u8; (high - low) / PAGE_SIZE];
+            assert_eq!(unsafe {libc::mincore(low as *mut libc::c_void, high - low, resident.as_mut_ptr())}, 0);
+            assert!(resident.iter().all(|page| page & 1 == 1));     // ガードページを除く全ページが常駐
+
+            send(id, 0);
+            join(id);       // join から戻った時点で終了した actor のスタックは解放済み
+            assert_eq!(locked_kb(), before);        // 終了時に固定は解除される
+        });
+    }
+
+    #[test]
+    fn total_stack_bytes_follows_spawns_and_exits() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let base = total_stack_bytes();     // root のスタック
+            let ids: Vec<u64> = (1..=3).map(|n| {
+                let id = spawn(wait_one, STACK);
+                assert_eq!(total_stack_bytes(), base + n * STACK);      // ガードページ込み
+                id
+            }).collect();
+            for (n, id) in ids.into_iter().enumerate() {
+                send(id, 0);
+                join(id);
+                assert_eq!(total_stack_bytes(), base + (2 - n) * STACK);
+            }
+        });
+        assert_eq!(total_stack_bytes(), 0);
+    }
+
+    #[test]
+    fn switching_to_drop_oldest_keeps_the_two_newest() {
+        let _s = serial();
+        set_config(RuntimeConfig { send_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let me = current_id();
+            for msg in 1..=5 {
+                send(me, msg);      // 自分に溢れさせる
+            }
+            set_my_mailbox_policy(MailboxPolicy::DropOldest(2));        // 現在の backlog も容量に合わせて切り詰める
+            assert_eq!(mailbox_len(me), 2);
+            send(me, 6);        // 以降の send も新しい扱いに従う
+            assert_eq!((0..2).map(|_| receive().unwrap()).collect::<Vec<_>>(), vec![5, 6]);
+            set_my_mailbox_policy(MailboxPolicy::Unbounded);
+            for msg in 1..=3 {
+                send(me, msg);
+            }
+            assert_eq!((0..3).map(|_| receive().unwrap()).collect::<Vec<_>>(), vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn a_tracked_message_truncated_by_a_new_policy_fails() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let receiver = spawn_fn(|| {
+                set_my_mailbox_policy(MailboxPolicy::Bounded(1));       // 古い方の 1 個だけを残す
+                receive().unwrap()
+            }, STACK);
+            let first = send_tracked(receiver, 1);
+            let second = send_tracked(receiver, 2);
+            assert_eq!(join(receiver), ExitReason::Normal(1));
+            assert!(wait_ack(first));
+            assert!(!wait_ack(second));
+        });
+    }
+
+    #[test]
+    fn a_send_rejected_by_a_full_bounded_mailbox_is_dropped_and_counted() {
+        let _s = serial();
+        set_config(RuntimeConfig { spawn_yields: false, send_yields: false, ..RuntimeConfig::new() });
+        with_runtime(|| {
+            let receiver = ActorBuilder::new().stack(STACK).mailbox(MailboxKind::Bounded(1)).suspended().spawn(wait_one);
+            let first = send_tracked(receiver, 1);
+            let second = send_tracked(receiver, 2);     // 満杯: 積まれず起床もしない
+            assert_eq!((mailbox_len(receiver), dropped_messages(receiver)), (1, 1));
+            assert!(!wait_ack(second));
+            assert!(resume(receiver));
+            join(receiver);
+            assert!(wait_ack(first));
+        });
+    }
+}
@@ -1,4 +1,5 @@
 mod green;
+mod platform;
 
 /// AArch64 アーキテクチャ上で動作するシングルスレッド協調的マルチタスク (actor model)
 
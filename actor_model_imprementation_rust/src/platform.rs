@@ -0,0 +1,142 @@
+use nix::errno::Errno;
+use nix::sys::mman::{mlock, mprotect, munlock, ProtFlags};
+use std::alloc::{alloc, dealloc, Layout};
+use std::ffi::c_void;
+use std::ptr::NonNull;
+
+/*  OS 依存部分 (スタック領域の確保・解放、ガードページの保護、ページサイズ) の抽象化
+    green.rs はスタックの確保とガードページの設定をすべてこの trait 経由で行う
+    -> 独自のスタックアロケータを使いたい環境や mprotect がない環境では、独自の実装を set_platform() で差し替える
+    ガードページを使わない場合は protect / unprotect を何もせず Ok(()) を返す実装にする (RuntimeConfig::guard_pages = 0 でも可)
+    コンテキストスイッチ (asm/context.s) はこの trait の対象外
+    no_std には対応していない: green.rs の本体は std (HashMap, Mutex, OS スレッド, eprintln など) に依存し、
+    この trait のエラーも nix::errno::Errno のまま -> std feature による core の分離と crate 独自のエラー型は未実装
+*/
+pub trait Platform: Sync {
+    fn page_size(&self) -> usize;
+
+    // layout の大きさとアライメントを満たすスタック領域を確保; 失敗したら null を返す
+    unsafe fn alloc_stack(&self, layout: Layout) -> *mut u8;
+
+    unsafe fn dealloc_stack(&self, stack: *mut u8, layout: Layout);
+
+    // stack から len バイトをアクセス不可にする (ガードページ)
+    unsafe fn protect(&self, stack: *mut u8, len: usize) -> Result<(), Errno>;
+
+    // protect した領域を読み書き可能に戻す: dealloc_stack の前に呼ばれる
+    unsafe fn unprotect(&self, stack: *mut u8, len: usize) -> Result<(), Errno>;
+
+    // actor の管理領域 (Context) の確保・解放: 既定は global allocator; 大量の spawn 向けに arena などへ差し替えられる
+    unsafe fn alloc_context(&self, layout: Layout) -> *mut u8 {
+        alloc(layout)
+    }
+
+    unsafe fn dealloc_context(&self, ptr: *mut u8, layout: Layout) {
+        dealloc(ptr, layout);
+    }
+
+    // stack から len バイトを物理メモリに固定する (spawn_mlocked); 対応しない環境では ENOSYS
+    unsafe fn lock(&self, _stack: *mut u8, _len: usize) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    // lock した領域の固定を解除する: dealloc_stack の前に呼ばれる
+    unsafe fn unlock(&self, _stack: *mut u8, _len: usize) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+pub const PAGE_SIZE: usize = 4 * 1024;      // 4KiB: Linux の仮想メモリ
+
+// syscall が signal で中断された (EINTR) 場合は一定回数まで再試行; それ以外のエラーや上限超過はそのまま返す
+const EINTR_RETRIES: usize = 8;
+
+fn retry_eintr<T>(mut f: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(Errno::EINTR) if retries < EINTR_RETRIES => retries += 1,
+            result => return result,
+        }
+    }
+}
+
+// 標準の実装: global allocator でスタックを確保し、mprotect でガードページを設定
+// mprotect / mlock / munlock はすべて retry_eintr を通す (signal で中断されても spawn やスタックの解放を失敗させない)
+pub struct LinuxPlatform;
+
+impl Platform for LinuxPlatform {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    unsafe fn alloc_stack(&self, layout: Layout) -> *mut u8 {
+        alloc(layout)
+    }
+
+    unsafe fn dealloc_stack(&self, stack: *mut u8, layout: Layout) {
+        dealloc(stack, layout);
+    }
+
+    unsafe fn protect(&self, stack: *mut u8, len: usize) -> Result<(), Errno> {
+        let guard = NonNull::new(stack as *mut c_void).unwrap();
+        retry_eintr(|| mprotect(guard, len, ProtFlags::PROT_NONE))
+    }
+
+    unsafe fn unprotect(&self, stack: *mut u8, len: usize) -> Result<(), Errno> {
+        let guard = NonNull::new(stack as *mut c_void).unwrap();
+        retry_eintr(|| mprotect(guard, len, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE))
+    }
+
+    unsafe fn lock(&self, stack: *mut u8, len: usize) -> Result<(), Errno> {
+        let region = NonNull::new(stack as *mut c_void).unwrap();
+        retry_eintr(|| mlock(region, len))
+    }
+
+    unsafe fn unlock(&self, stack: *mut u8, len: usize) -> Result<(), Errno> {
+        let region = NonNull::new(stack as *mut c_void).unwrap();
+        retry_eintr(|| munlock(region, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 指定した回数だけ err を返し、その後は成功する syscall の mock
+    fn failing(times: usize, err: Errno) -> impl FnMut() -> nix::Result<usize> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            if calls <= times { Err(err) } else { Ok(calls) }
+        }
+    }
+
+    #[test]
+    fn eintr_is_retried_until_success() {
+        assert_eq!(retry_eintr(failing(2, Errno::EINTR)), Ok(3));
+    }
+
+    #[test]
+    fn other_errors_and_too_many_retries_are_returned() {
+        assert_eq!(retry_eintr(failing(1, Errno::ENOMEM)), Err(Errno::ENOMEM));
+        assert_eq!(retry_eintr(failing(EINTR_RETRIES + 1, Errno::EINTR)), Err(Errno::EINTR));
+    }
+
+    #[test]
+    fn linux_platform_protects_and_locks_a_real_stack() {
+        let layout = Layout::from_size_align(2 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        unsafe {
+            let stack = LinuxPlatform.alloc_stack(layout);
+            assert!(!stack.is_null());
+            assert_eq!(LinuxPlatform.protect(stack, PAGE_SIZE), Ok(()));
+            assert_eq!(LinuxPlatform.unprotect(stack, PAGE_SIZE), Ok(()));
+            // RLIMIT_MEMLOCK によっては mlock できない (EPERM / ENOMEM) が、EINTR のまま返ることはない
+            match LinuxPlatform.lock(stack.add(PAGE_SIZE), PAGE_SIZE) {
+                Ok(()) => assert_eq!(LinuxPlatform.unlock(stack.add(PAGE_SIZE), PAGE_SIZE), Ok(())),
+                Err(errno) => assert_ne!(errno, Errno::EINTR),
+            }
+            LinuxPlatform.dealloc_stack(stack, layout);
+        }
+    }
+}